@@ -0,0 +1,54 @@
+//! Many threads allocating from one label, with and without thread caches
+//!
+//! Run with `cargo bench --bench threads`
+
+#![feature(allocator_api)]
+
+use std::{
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rsbmalloc::{ProtectionLabel, ProtectionLevel};
+
+const THREADS: usize = 32;
+const ROUNDS: usize = 2_000;
+const LIVE: usize = 64;
+
+fn run(thread_cache: bool) -> Duration {
+    let label = ProtectionLabel::builder()
+        .level(ProtectionLevel::ReadWrite)
+        .thread_cache(thread_cache)
+        .create()
+        .expect("Unable to create a protection label");
+    let start = Instant::now();
+    let workers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let label = label.clone();
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let boxes: Vec<_> = (0..LIVE)
+                        .map(|i| Box::new_in([round + i; 4], label.clone()))
+                        .collect();
+                    black_box(&boxes);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let ops = THREADS * ROUNDS * LIVE;
+    for (name, thread_cache) in [("shared bins", false), ("thread caches", true)] {
+        let elapsed = run(thread_cache);
+        println!(
+            "{name:>14}: {THREADS} threads, {ops} alloc/free pairs in {elapsed:?} ({:.1} ns/pair)",
+            elapsed.as_nanos() as f64 / ops as f64
+        );
+    }
+}
@@ -1,111 +1,362 @@
-use core::alloc::Allocator;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
 use core::{alloc::Layout, mem, ptr, ptr::NonNull};
-use std::alloc::AllocError;
 
+use bin::Bin;
 use libc::c_int;
-use page_allocator::PageAllocator;
-use spin::Mutex;
+pub(crate) use meta::{MetaMap, MetaVec};
+use page_allocator::{PageAllocator, HUGE_PAGE_SIZE, PAGE_SIZE};
+use quarantine::QuarantineList;
+use size_classes::slot_align;
 use static_assertions::assert_impl_all;
 
+use crate::registry::LabelId;
+use crate::stats::{BinStats, LabelStats};
+
+mod arena;
+mod bin;
+mod meta;
 mod page_allocator;
+mod quarantine;
+mod size_classes;
+
+pub use page_allocator::HugePages;
+pub use quarantine::Quarantine;
+pub use size_classes::SizeClasses;
 
+/// The default size of the chunks bins carve their slots from
 const RSB_CHUNK_SIZE: usize = 0x10000;
 const MAX_ALIGN: usize = 0x1000;
 
+/// Options controlling the behaviour of an allocator
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Options {
+    /// Keep a record of every large allocation so that the heap can be walked
+    pub(crate) track_allocations: bool,
+    /// Validate every free against the bins, aborting on invalid or double frees
+    pub(crate) hardened: bool,
+    /// Hold freed slots back from reuse for a while
+    pub(crate) quarantine: Option<Quarantine>,
+    /// Serve small allocations from per-thread caches.  Ignored in hardened
+    /// mode or with a quarantine, since those need to see every free.
+    pub(crate) thread_cache: bool,
+    /// The slot sizes of the bins
+    pub(crate) size_classes: SizeClasses,
+    /// The size of the chunks mapped for bins, [`RSB_CHUNK_SIZE`] if unset
+    pub(crate) chunk_size: Option<usize>,
+    /// Whether to back large mappings with huge pages
+    pub(crate) huge_pages: HugePages,
+    /// Reserve an arena of this many bytes to commit all memory from
+    pub(crate) reserve: Option<usize>,
+}
+
+impl Options {
+    /// Whether every bin's chunks can be rounded up to whole pages without
+    /// overflowing
+    pub(crate) fn chunk_size_is_valid(&self) -> bool {
+        self.chunk_size.map_or(true, |chunk_size| {
+            bin_chunk_size(chunk_size, 0, self.huge_pages).is_some()
+        })
+    }
+}
+
+/// Generate the per-allocator secret used to mangle free list pointers
+fn random_secret() -> usize {
+    let mut secret: usize = 0;
+    let got = unsafe {
+        libc::getrandom(
+            &mut secret as *mut usize as *mut libc::c_void,
+            mem::size_of::<usize>(),
+            0,
+        )
+    };
+    if got != mem::size_of::<usize>() as isize {
+        // No getrandom, fall back to whatever ASLR and the clock give us
+        let mut now: libc::timespec = unsafe { mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        secret = (&secret as *const usize as usize).rotate_left(32)
+            ^ (now.tv_nsec as usize)
+            ^ (now.tv_sec as usize).rotate_left(17);
+    }
+    secret
+}
+
+/// Mangle a free list pointer stored at `pos`, in the style of glibc's
+/// safe-linking.  Mixing in the (ASLR randomised) address of the slot and a
+/// per-allocator secret means a use-after-free write can't simply point the
+/// free list at an address of the attacker's choosing.
+#[inline(always)]
+fn protect(pos: *const u8, ptr: usize, secret: usize) -> usize {
+    ((pos as usize) >> 12) ^ ptr ^ secret
+}
+
+/// Undo [`protect`]
+#[inline(always)]
+fn reveal(pos: *const u8, mangled: usize, secret: usize) -> usize {
+    protect(pos, mangled, secret)
+}
+
+/// Writes straight to stderr, without touching the heap or needing `std`
+struct Stderr;
+
+impl fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written =
+                unsafe { libc::write(libc::STDERR_FILENO, bytes.as_ptr() as _, bytes.len()) };
+            if written <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+/// Report an unrecoverable error and abort the process
+#[cold]
+pub(crate) fn fatal(args: fmt::Arguments) -> ! {
+    let _ = writeln!(Stderr, "rsbmalloc: {args}");
+    unsafe { libc::abort() }
+}
+
+/// Report heap corruption and abort the process, there's no safe way to
+/// continue once the heap is known to be inconsistent.
+#[cold]
+fn corruption(args: fmt::Arguments) -> ! {
+    fatal(args)
+}
+
 pub struct RSBMalloc {
+    id: LabelId,
     bins: Bins,
     pages: PageAllocator,
+    quarantine: Option<QuarantineList>,
+    quarantine_pkey: Option<c_int>,
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    thread_cache: bool,
 }
 
 assert_impl_all!(RSBMalloc: Send, Sync);
 
 impl RSBMalloc {
     /// # Safety
-    /// pkey must be a valid protection label
-    pub unsafe fn new(pkey: c_int) -> Self {
+    /// pkey must be a valid protection label, as must quarantine_pkey if
+    /// the quarantine is to deny access to its slots
+    pub unsafe fn new(pkey: c_int, quarantine_pkey: Option<c_int>, options: Options) -> Self {
+        let quarantine = options.quarantine.map(QuarantineList::new);
+        let quarantine_pkey = quarantine
+            .as_ref()
+            .filter(|q| q.denies_access())
+            .and(quarantine_pkey);
+        let id = LabelId::next();
         Self {
-            bins: Bins::new(),
-            pages: PageAllocator::new(pkey),
+            id,
+            bins: Bins::new(options, random_secret()),
+            pages: PageAllocator::new(pkey, id, options.track_allocations, options.huge_pages),
+            quarantine,
+            quarantine_pkey,
+            #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+            thread_cache: options.thread_cache
+                && !options.hardened
+                && options.quarantine.is_none()
+                && !options.track_allocations,
+        }
+    }
+
+    /// Unique for the life of the process, unlike the protection key
+    pub fn id(&self) -> LabelId {
+        self.id
+    }
+
+    /// The bin index to cache allocations of `layout` in, if they can be
+    /// served from a thread cache at all
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) fn cache_index(&self, layout: Layout) -> Option<usize> {
+        if !self.thread_cache || layout.align() > MAX_ALIGN {
+            return None;
+        }
+        self.bins
+            .index(layout.pad_to_align().size(), layout.align())
+    }
+
+    /// The slot size of the bin at `index`
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) fn slot_size(&self, index: usize) -> usize {
+        self.bins.by_index(index).slot_size()
+    }
+
+    /// Allocate up to `count` slots from the bin at `index` into `out`
+    ///
+    /// # Safety
+    /// As for allocation, the caller must have access to this allocator's
+    /// memory
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) unsafe fn refill(&self, index: usize, out: &mut Vec<*mut u8>, count: usize) {
+        self.bins
+            .by_index(index)
+            .alloc_batch(&self.pages, out, count);
+    }
+
+    /// Return slots to the bin at `index`
+    ///
+    /// # Safety
+    /// The slots must have been allocated from that bin, and the caller must
+    /// have write access to this allocator's memory
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) unsafe fn flush(&self, index: usize, slots: &[*mut u8]) {
+        self.bins.by_index(index).dealloc_batch(slots);
+    }
+
+    /// Retire a freed slot into quarantine, releasing any which have been
+    /// there long enough back to their bins
+    unsafe fn quarantine_slot(&self, quarantine: &QuarantineList, bin: &Bin, ptr: *mut u8) {
+        bin.retire(ptr);
+        let slot_size = bin.slot_size();
+        let protect = self.quarantine_pkey.filter(|_| slot_size % *PAGE_SIZE == 0);
+        if let Some(pkey) = protect {
+            self.pages.rekey(ptr, slot_size, pkey);
         }
+        quarantine.push(ptr, slot_size, |ptr, slot_size| {
+            if protect.is_some() {
+                self.pages.rekey(ptr, slot_size, self.pages.pkey());
+            }
+            if let Some(bin) = self.bins.bin_of_slot(slot_size) {
+                bin.release(ptr);
+            }
+        });
+    }
+
+    pub fn tracks_allocations(&self) -> bool {
+        self.pages.tracks_allocations()
+    }
+
+    /// Call `func` with the address and size of every live allocation.
+    ///
+    /// Bin allocations are reported with the size of their slot, large
+    /// allocations are only reported if allocation tracking is enabled.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have read access to the memory of this allocator,
+    /// since the free lists are stored within it.
+    pub unsafe fn walk<F>(&self, mut func: F)
+    where
+        F: FnMut(NonNull<u8>, usize),
+    {
+        let quarantined = match &self.quarantine {
+            Some(quarantine) => quarantine.slots_held(),
+            None => MetaVec::new(),
+        };
+        self.bins.walk(&mut func, &quarantined);
+        self.pages.walk_large(&mut func);
     }
 
     /// # Safety
     /// Only call this just before releasing the pkey back to the OS
     pub unsafe fn free_all(&self) {
+        if let Some(quarantine) = &self.quarantine {
+            quarantine.clear();
+        }
         self.bins.free_all(&self.pages);
     }
+
+    /// Reserve an arena of `size` bytes to commit every later mapping from,
+    /// returning false if the address space isn't available
+    ///
+    /// # Safety
+    /// Must be called before anything is allocated
+    pub unsafe fn reserve(&mut self, size: usize) -> bool {
+        self.pages.reserve(size)
+    }
+
+    /// The reserved arena's address range, if there is one
+    pub fn arena(&self) -> Option<NonNull<[u8]>> {
+        self.pages.arena().map(|arena| unsafe {
+            NonNull::slice_from_raw_parts(NonNull::new_unchecked(arena.base()), arena.size())
+        })
+    }
+
+    /// Whether `ptr` lies within the reserved arena, or `None` if there is
+    /// no arena to check against
+    pub fn arena_contains(&self, ptr: *const u8) -> Option<bool> {
+        self.pages.arena().map(|arena| arena.contains(ptr))
+    }
+
+    /// The protection key used for quarantined slots, if any
+    pub fn quarantine_pkey(&self) -> Option<c_int> {
+        self.quarantine_pkey
+    }
+
+    pub fn stats(&self) -> LabelStats {
+        LabelStats {
+            bins: self.bins.stats(),
+            large_allocations: self.pages.large_allocations(),
+            large_bytes: self.pages.large_bytes(),
+            mapped_bytes: self.pages.mapped_bytes(),
+            hugetlb_bytes: self.pages.hugetlb_bytes(),
+            transparent_huge_page_bytes: self.pages.transparent_huge_page_bytes(),
+            reserved_bytes: self.pages.arena().map_or(0, |arena| arena.size()),
+            quarantined_slots: self.quarantine.as_ref().map_or(0, |q| q.slots()),
+            quarantined_bytes: self.quarantine.as_ref().map_or(0, |q| q.bytes()),
+        }
+    }
 }
 
-unsafe impl Allocator for RSBMalloc {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+/// The allocator couldn't satisfy a request, converted to the error type of
+/// whichever `Allocator` trait is being implemented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AllocError;
+
+/// These follow the `Allocator` trait, which has to be implemented on the
+/// label itself for both the nightly and `allocator-api2` flavours
+impl RSBMalloc {
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         if layout.align() > MAX_ALIGN {
             return Err(AllocError);
         }
-        let size = layout.pad_to_align().size();
-        let bins = &self.bins;
         let ptr = unsafe {
-            match size {
-                0..=4 => bins.bin4.alloc(&self.pages),
-                5..=8 => bins.bin8.alloc(&self.pages),
-                9..=16 => bins.bin16.alloc(&self.pages),
-                17..=32 => bins.bin32.alloc(&self.pages),
-                33..=64 => bins.bin64.alloc(&self.pages),
-                65..=128 => bins.bin128.alloc(&self.pages),
-                129..=256 => bins.bin256.alloc(&self.pages),
-                257..=512 => bins.bin512.alloc(&self.pages),
-                513..=1024 => bins.bin1024.alloc(&self.pages),
-                1025..=2048 => bins.bin2048.alloc(&self.pages),
-                2049..=4096 => bins.bin4096.alloc(&self.pages),
-                4097..=8192 => bins.bin8192.alloc(&self.pages),
-                8193..=16384 => bins.bin16384.alloc(&self.pages),
-                16385..=0x8000 => bins.bin32ki.alloc(&self.pages),
-                0x8001..=0x10000 => bins.bin64ki.alloc(&self.pages),
-                _ => self.pages.alloc(layout),
+            match self.bins.bin(layout) {
+                Some(bin) => bin.alloc(&self.pages),
+                None => self.pages.alloc_large(layout),
             }
         };
         let ptr = NonNull::new(ptr).ok_or(AllocError)?;
-        Ok(NonNull::slice_from_raw_parts(ptr, size))
+        Ok(NonNull::slice_from_raw_parts(
+            ptr,
+            layout.pad_to_align().size(),
+        ))
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        let size = layout.pad_to_align().size();
-        let bins = &self.bins;
+    /// # Safety
+    /// `ptr` must have been allocated from this allocator with `layout`
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         let ptr = ptr.as_ptr();
-        match size {
-            0..=4 => bins.bin4.dealloc(ptr),
-            5..=8 => bins.bin8.dealloc(ptr),
-            9..=16 => bins.bin16.dealloc(ptr),
-            17..=32 => bins.bin32.dealloc(ptr),
-            33..=64 => bins.bin64.dealloc(ptr),
-            65..=128 => bins.bin128.dealloc(ptr),
-            129..=256 => bins.bin256.dealloc(ptr),
-            257..=512 => bins.bin512.dealloc(ptr),
-            513..=1024 => bins.bin1024.dealloc(ptr),
-            1025..=2048 => bins.bin2048.dealloc(ptr),
-            2049..=4096 => bins.bin4096.dealloc(ptr),
-            4097..=8192 => bins.bin8192.dealloc(ptr),
-            8193..=16384 => bins.bin16384.dealloc(ptr),
-            16385..=0x8000 => bins.bin32ki.dealloc(ptr),
-            0x8001..=0x10000 => bins.bin64ki.dealloc(ptr),
-            _ => self.pages.dealloc(ptr, layout),
+        match (self.bins.bin(layout), &self.quarantine) {
+            (Some(bin), Some(quarantine)) => self.quarantine_slot(quarantine, bin, ptr),
+            (Some(bin), None) => bin.dealloc(ptr),
+            (None, _) => self.pages.dealloc_large(ptr, layout),
         }
     }
 
-    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+    pub fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let ptr = self.allocate(layout)?;
         // SAFETY: `alloc` returns a valid memory block
         unsafe {
-            ptr.as_non_null_ptr().as_ptr().write_bytes(0, ptr.len());
+            ptr.cast::<u8>().as_ptr().write_bytes(0, ptr.len());
         }
         Ok(ptr)
     }
 
-    unsafe fn grow(
+    /// # Safety
+    /// As for `Allocator::grow`
+    pub unsafe fn grow(
         &self,
         ptr: NonNull<u8>,
         old_layout: Layout,
         new_layout: Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+    ) -> Result<NonNull<[u8]>, AllocError> {
         debug_assert!(
             new_layout.size() >= old_layout.size(),
             "`new_layout.size()` must be greater than or equal to `old_layout.size()`"
@@ -115,10 +366,10 @@ unsafe impl Allocator for RSBMalloc {
             return Err(AllocError);
         }
 
-        if old_layout.pad_to_align().size() > RSB_CHUNK_SIZE {
+        if self.bins.bin(old_layout).is_none() {
             let new_ptr = self
                 .pages
-                .realloc(ptr.as_ptr(), old_layout, new_layout.size());
+                .realloc_large(ptr.as_ptr(), old_layout, new_layout.size());
             let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
             return Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()));
         }
@@ -131,19 +382,26 @@ unsafe impl Allocator for RSBMalloc {
         // deallocated, it cannot overlap `new_ptr`. Thus, the call to `copy_nonoverlapping` is
         // safe. The safety contract for `dealloc` must be upheld by the caller.
         unsafe {
-            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), old_layout.size());
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
             self.deallocate(ptr, old_layout);
         }
 
         Ok(new_ptr)
     }
 
-    unsafe fn grow_zeroed(
+    /// # Safety
+    /// As for `Allocator::grow_zeroed`
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+    pub unsafe fn grow_zeroed(
         &self,
         ptr: NonNull<u8>,
         old_layout: Layout,
         new_layout: Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+    ) -> Result<NonNull<[u8]>, AllocError> {
         debug_assert!(
             new_layout.size() >= old_layout.size(),
             "`new_layout.size()` must be greater than or equal to `old_layout.size()`"
@@ -161,19 +419,25 @@ unsafe impl Allocator for RSBMalloc {
         // deallocated, it cannot overlap `new_ptr`. Thus, the call to `copy_nonoverlapping` is
         // safe. The safety contract for `dealloc` must be upheld by the caller.
         unsafe {
-            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), old_layout.size());
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
             self.deallocate(ptr, old_layout);
         }
 
         Ok(new_ptr)
     }
 
-    unsafe fn shrink(
+    /// # Safety
+    /// As for `Allocator::shrink`
+    pub unsafe fn shrink(
         &self,
         ptr: NonNull<u8>,
         old_layout: Layout,
         new_layout: Layout,
-    ) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+    ) -> Result<NonNull<[u8]>, AllocError> {
         debug_assert!(
             new_layout.size() <= old_layout.size(),
             "`new_layout.size()` must be smaller than or equal to `old_layout.size()`"
@@ -187,7 +451,11 @@ unsafe impl Allocator for RSBMalloc {
         // deallocated, it cannot overlap `new_ptr`. Thus, the call to `copy_nonoverlapping` is
         // safe. The safety contract for `dealloc` must be upheld by the caller.
         unsafe {
-            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), new_layout.size());
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
             self.deallocate(ptr, old_layout);
         }
 
@@ -195,304 +463,288 @@ unsafe impl Allocator for RSBMalloc {
     }
 }
 
-#[derive(Default)]
+/// The size of the chunks a bin of `slot_size` byte slots maps, at least
+/// one slot and rounded up to whole pages, huge pages if enabled, or `None`
+/// if that's too big to map
+fn bin_chunk_size(chunk_size: usize, slot_size: usize, huge_pages: HugePages) -> Option<usize> {
+    let page = match huge_pages {
+        HugePages::Never => *PAGE_SIZE,
+        _ => HUGE_PAGE_SIZE,
+    };
+    let size = chunk_size.max(slot_size).checked_add(page - 1)? / page * page;
+    Layout::from_size_align(size, page)
+        .ok()
+        .map(|layout| layout.size())
+}
+
 pub(crate) struct Bins {
-    bin4: Bin<Slot4>,
-    bin8: Bin<Slot8>,
-    bin16: Bin<Slot16>,
-    bin32: Bin<Slot32>,
-    bin64: Bin<Slot64>,
-    bin128: Bin<Slot128>,
-    bin256: Bin<Slot256>,
-    bin512: Bin<Slot512>,
-    bin1024: Bin<Slot1024>,
-    bin2048: Bin<Slot2048>,
-    bin4096: Bin<Slot4096>,
-    bin8192: Bin<Slot8192>,
-    bin16384: Bin<Slot16384>,
-    bin32ki: Bin<Slot32Ki>,
-    bin64ki: Bin<Slot64Ki>,
+    /// The slot size of each bin, in ascending order
+    classes: &'static [usize],
+    bins: MetaVec<Bin>,
 }
 
 impl Bins {
-    fn new() -> Self {
-        Self {
-            bin4: Bin::new(),
-            bin8: Bin::new(),
-            bin16: Bin::new(),
-            bin32: Bin::new(),
-            bin64: Bin::new(),
-            bin128: Bin::new(),
-            bin256: Bin::new(),
-            bin512: Bin::new(),
-            bin1024: Bin::new(),
-            bin2048: Bin::new(),
-            bin4096: Bin::new(),
-            bin8192: Bin::new(),
-            bin16384: Bin::new(),
-            bin32ki: Bin::new(),
-            bin64ki: Bin::new(),
-        }
+    fn new(options: Options, secret: usize) -> Self {
+        let hardened = options.hardened;
+        let serialise = options.quarantine.is_some_and(|q| q.deny_access);
+        let classes = options.size_classes.table();
+        let chunk_size = options.chunk_size.unwrap_or(RSB_CHUNK_SIZE);
+        let mut bins = MetaVec::with_capacity(classes.len());
+        bins.extend(classes.iter().map(|&slot_size| {
+            let chunk_size = bin_chunk_size(chunk_size, slot_size, options.huge_pages)
+                .expect("chunk size is checked by the builder");
+            Bin::new(slot_size, chunk_size, hardened, serialise, secret)
+        }));
+        Self { classes, bins }
     }
 
     fn free_all(&self, pages: &PageAllocator) {
-        self.bin4.free_all(pages);
-        self.bin8.free_all(pages);
-        self.bin16.free_all(pages);
-        self.bin32.free_all(pages);
-        self.bin64.free_all(pages);
-        self.bin128.free_all(pages);
-        self.bin256.free_all(pages);
-        self.bin512.free_all(pages);
-        self.bin1024.free_all(pages);
-        self.bin2048.free_all(pages);
-        self.bin4096.free_all(pages);
-        self.bin8192.free_all(pages);
-        self.bin16384.free_all(pages);
-        self.bin32ki.free_all(pages);
-        self.bin64ki.free_all(pages);
+        for bin in &self.bins {
+            bin.free_all(pages);
+        }
     }
-}
-
-pub(crate) trait Slot {
-    /// Size is not always the size of the type
-    /// For example, a 4 byte size would be valid but the type would be
-    /// pointer-sized
-    const SIZE: usize;
-    unsafe fn buf(&mut self) -> *mut u8;
-    unsafe fn next(&self) -> Option<NonNull<Self>>;
-    unsafe fn set_next(&mut self, next: Option<NonNull<Self>>);
-}
-
-macro_rules! slot {
-    ($name:ident, $len:literal, $align:literal) => {
-        slot_align!($name, $len, $align);
-    };
-    ($name:ident, $len:literal) => {
-        slot_align!($name, $len, $len);
-    };
-}
 
-macro_rules! slot_align {
-    ($name:ident, $len:literal,$align:literal) => {
-        #[repr(align($align))]
-        pub(crate) union $name {
-            pub(crate) buf: [u8; $len],
-            pub(crate) next: Option<NonNull<$name>>,
-        }
+    /// The index of the bin serving allocations of `size` bytes aligned to
+    /// `align`, if any
+    fn index(&self, size: usize, align: usize) -> Option<usize> {
+        let first = self.classes.partition_point(|&class| class < size);
+        (first..self.classes.len()).find(|&index| slot_align(self.classes[index]) >= align)
+    }
 
-        impl Slot for $name {
-            const SIZE: usize = $len;
+    fn by_index(&self, index: usize) -> &Bin {
+        &self.bins[index]
+    }
 
-            #[inline(always)]
-            unsafe fn buf(&mut self) -> *mut u8 {
-                &mut self.buf[..] as *mut [u8] as *mut u8
-            }
+    /// The bin serving allocations of `layout`, if any
+    fn bin(&self, layout: Layout) -> Option<&Bin> {
+        self.index(layout.pad_to_align().size(), layout.align())
+            .map(|index| self.by_index(index))
+    }
 
-            #[inline(always)]
-            unsafe fn next(&self) -> Option<NonNull<$name>> {
-                self.next
-            }
+    /// The bin whose slots are `slot_size` bytes
+    fn bin_of_slot(&self, slot_size: usize) -> Option<&Bin> {
+        self.classes
+            .binary_search(&slot_size)
+            .ok()
+            .map(|index| self.by_index(index))
+    }
 
-            #[inline(always)]
-            unsafe fn set_next(&mut self, next: Option<NonNull<$name>>) {
-                self.next = next;
-            }
+    unsafe fn walk(&self, func: &mut dyn FnMut(NonNull<u8>, usize), quarantined: &[*mut u8]) {
+        for bin in &self.bins {
+            bin.walk(func, quarantined);
         }
-    };
-}
+    }
 
-struct Slice {
-    ptr: *mut u8,
-    len: usize,
+    fn stats(&self) -> Vec<BinStats> {
+        self.bins.iter().map(Bin::stats).collect()
+    }
 }
 
-unsafe impl Send for Slice {}
-
-struct FreeList<S: Slot> {
-    ptr: *mut S,
-}
+#[cfg(test)]
+mod test {
 
-unsafe impl<S: Slot> Send for FreeList<S> {}
+    use super::*;
 
-impl<S: Slot> FreeList<S> {
-    fn exists(&self) -> bool {
-        !self.ptr.is_null()
+    #[repr(align(512))]
+    struct Big {
+        _contents: [u8; 512],
     }
-    const fn null() -> Self {
-        Self {
-            ptr: core::ptr::null_mut(),
+
+    impl Big {
+        fn new() -> Self {
+            Self {
+                _contents: [0; 512],
+            }
         }
     }
-    unsafe fn get_next(&self) -> Option<NonNull<S>> {
-        (*self.ptr).next()
-    }
-    unsafe fn get_buf(&self) -> *mut u8 {
-        (*self.ptr).buf()
-    }
-    fn option_nn(&self) -> Option<NonNull<S>> {
-        NonNull::new(self.ptr)
-    }
-}
 
-impl<S: Slot> From<Option<NonNull<S>>> for FreeList<S> {
-    fn from(value: Option<NonNull<S>>) -> Self {
-        Self {
-            ptr: match value {
-                Some(nn) => nn.as_ptr(),
-                None => core::ptr::null_mut(),
-            },
+    /// Run `func` in a forked child, returning whether the child aborted
+    fn aborts<F: FnOnce()>(func: F) -> bool {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                func();
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGABRT
         }
     }
-}
-impl<S: Slot> From<*mut S> for FreeList<S> {
-    fn from(value: *mut S) -> Self {
-        Self { ptr: value }
-    }
-}
-
-struct Bin<S: Slot> {
-    free_head: Mutex<FreeList<S>>,
-    page: Mutex<Slice>,
-    pages: Mutex<Vec<(*mut u8, Layout)>>,
-}
 
-unsafe impl<S: Slot> Send for Bin<S> {}
-unsafe impl<S: Slot> Sync for Bin<S> {}
+    fn hardened() -> RSBMalloc {
+        unsafe {
+            RSBMalloc::new(
+                0,
+                None,
+                Options {
+                    hardened: true,
+                    ..Options::default()
+                },
+            )
+        }
+    }
 
-impl<S: Slot> Default for Bin<S> {
-    fn default() -> Self {
-        Self {
-            free_head: Mutex::new(FreeList::null()),
-            page: Mutex::new(Slice {
-                ptr: core::ptr::null_mut(),
-                len: 0,
-            }),
-            pages: Mutex::new(Vec::new()),
+    #[test]
+    fn hardened_reuse() {
+        let alloc = hardened();
+        let layout = Layout::new::<u64>();
+        let first = alloc.allocate(layout).unwrap().cast::<u8>();
+        unsafe { alloc.deallocate(first, layout) };
+        let second = alloc.allocate(layout).unwrap().cast::<u8>();
+        assert_eq!(first, second);
+        unsafe {
+            alloc.deallocate(second, layout);
+            alloc.free_all();
         }
     }
-}
 
-slot!(Slot4, 0x4);
-slot!(Slot8, 0x8, 0x4);
-slot!(Slot16, 0x10);
-slot!(Slot32, 0x20);
-slot!(Slot64, 0x40);
-slot!(Slot128, 0x80);
-slot!(Slot256, 0x100);
-slot!(Slot512, 0x200);
-slot!(Slot1024, 0x400);
-slot!(Slot2048, 0x800);
-slot!(Slot4096, 0x1000);
-slot!(Slot8192, 0x2000, 0x1000);
-slot!(Slot16384, 0x4000, 0x1000);
-slot!(Slot32Ki, 0x8000, 0x1000);
-slot!(Slot64Ki, 0x10000, 0x1000);
-
-impl<S: Slot> Bin<S> {
-    fn add_one(&self, pages: &PageAllocator) -> *mut S {
-        let slot_size = mem::size_of::<S>();
-        let mut page = self.page.lock();
-        if !page.ptr.is_null() && page.len >= slot_size {
-            let ret = page.ptr as *mut S;
+    #[test]
+    fn hardened_double_free() {
+        assert!(aborts(|| {
+            let alloc = hardened();
+            let layout = Layout::new::<u64>();
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
             unsafe {
-                page.ptr = page.ptr.add(slot_size);
-                page.len -= slot_size;
+                alloc.deallocate(ptr, layout);
+                alloc.deallocate(ptr, layout);
             }
-            return ret;
-        }
-        unsafe {
-            let layout = Layout::from_size_align_unchecked(RSB_CHUNK_SIZE, mem::align_of::<S>());
-            let ptr = pages.alloc(layout);
-            self.pages.lock().push((ptr, layout));
-            let ret = ptr as *mut S;
-            page.ptr = ptr.add(slot_size);
-            page.len = RSB_CHUNK_SIZE - slot_size;
-            ret
-        }
+        }));
     }
 
-    /// Allocates a pointer with size SIZE
-    unsafe fn alloc(&self, pages: &PageAllocator) -> *mut u8 {
-        let mut free_head = self.free_head.lock();
-        if free_head.exists() {
-            let buf = free_head.get_buf();
-            (*free_head) = free_head.get_next().into();
-            buf
-        } else {
-            drop(free_head);
-            (*self.add_one(pages)).buf()
-        }
+    #[test]
+    fn hardened_misaligned_free() {
+        assert!(aborts(|| {
+            let alloc = hardened();
+            let layout = Layout::new::<u64>();
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+            unsafe { alloc.deallocate(ptr.add(4), Layout::new::<u32>()) };
+        }));
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8) {
-        let slot_ptr = ptr as *mut S;
-        let mut free_head = self.free_head.lock();
-        (*slot_ptr).set_next((*free_head).option_nn());
-        (*free_head) = FreeList::from(slot_ptr);
+    #[test]
+    fn hardened_unissued_free() {
+        assert!(aborts(|| {
+            let alloc = hardened();
+            let layout = Layout::new::<u64>();
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+            // The next slot of the chunk, which the bump pointer hasn't
+            // reached yet
+            unsafe { alloc.deallocate(ptr.add(8), layout) };
+        }));
     }
 
-    fn new() -> Self {
-        Self {
-            free_head: Mutex::new(FreeList::null()),
-            page: Mutex::new(Slice {
-                ptr: core::ptr::null_mut(),
-                len: 0,
-            }),
-            pages: Mutex::new(Vec::new()),
+    #[test]
+    fn hardened_foreign_free() {
+        assert!(aborts(|| {
+            let alloc = hardened();
+            let other = hardened();
+            let layout = Layout::new::<u64>();
+            // Make sure the bin has a chunk to search
+            let _mine = alloc.allocate(layout).unwrap();
+            let ptr = other.allocate(layout).unwrap().cast::<u8>();
+            unsafe { alloc.deallocate(ptr, layout) };
+        }));
+    }
+
+    #[test]
+    fn free_list_is_mangled() {
+        let alloc = unsafe { RSBMalloc::new(0, None, Options::default()) };
+        let layout = Layout::new::<[u64; 8]>();
+        let first = alloc.allocate(layout).unwrap().cast::<u8>();
+        let second = alloc.allocate(layout).unwrap().cast::<u8>();
+        unsafe {
+            alloc.deallocate(first, layout);
+            alloc.deallocate(second, layout);
+            let stored = second.cast::<usize>().read();
+            assert_ne!(stored, first.as_ptr() as usize);
+            assert_eq!(alloc.allocate(layout).unwrap().cast::<u8>(), second);
+            assert_eq!(alloc.allocate(layout).unwrap().cast::<u8>(), first);
+            alloc.free_all();
         }
     }
 
-    fn free_all(&self, pages: &PageAllocator) {
-        // Note, the order here is important to ensure we don't deadlock
-        // though frankly this is part of Drop so we should be fine
-        let mut fh = self.free_head.lock();
-        let mut p = self.page.lock();
-        let mut ps = self.pages.lock();
-        *fh = FreeList::null();
-        *p = Slice {
-            ptr: core::ptr::null_mut(),
-            len: 0,
-        };
-        for (page, layout) in ps.drain(..) {
+    #[test]
+    fn corrupt_free_list() {
+        assert!(aborts(|| {
+            let alloc = unsafe { RSBMalloc::new(0, None, Options::default()) };
+            let layout = Layout::new::<[u64; 8]>();
+            let first = alloc.allocate(layout).unwrap().cast::<u8>();
+            let second = alloc.allocate(layout).unwrap().cast::<u8>();
             unsafe {
-                pages.dealloc(page, layout);
+                alloc.deallocate(first, layout);
+                alloc.deallocate(second, layout);
+                // A use-after-free write over the stored pointer
+                let next = second.cast::<usize>();
+                next.write(next.read() ^ 0x8);
+                let _ = alloc.allocate(layout);
             }
-        }
+        }));
     }
-}
-
-#[cfg(test)]
-mod test {
-
-    use super::*;
 
-    #[repr(align(512))]
-    struct Big {
-        _contents: [u8; 512],
+    #[test]
+    fn concurrent_free_lists() {
+        // Hardened mode aborts if the same slot is ever popped twice
+        let alloc = hardened();
+        std::thread::scope(|scope| {
+            for t in 0..8u64 {
+                let alloc = &alloc;
+                scope.spawn(move || {
+                    let layout = Layout::new::<u64>();
+                    for round in 0..2_000 {
+                        let ptrs: Vec<_> = (0..16)
+                            .map(|_| {
+                                let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+                                unsafe { ptr.cast::<u64>().write(t * 100_000 + round) };
+                                ptr
+                            })
+                            .collect();
+                        for ptr in ptrs {
+                            unsafe {
+                                assert_eq!(ptr.cast::<u64>().read(), t * 100_000 + round);
+                                alloc.deallocate(ptr, layout);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let stats = alloc.stats();
+        assert_eq!(stats.bins[0].live_slots, 0);
+        unsafe { alloc.free_all() };
     }
 
-    impl Big {
-        fn new() -> Self {
-            Self {
-                _contents: [0; 512],
+    /// Grow a buffer of `T` the way `Vec` would, checking nothing is lost
+    fn push_all<T>(
+        alloc: &RSBMalloc,
+        count: usize,
+        make: impl Fn(usize) -> T,
+        check: impl Fn(&T, usize),
+    ) {
+        let mut layout = Layout::array::<T>(1).unwrap();
+        let mut ptr = alloc.allocate(layout).unwrap().cast::<T>();
+        for i in 0..count {
+            if i * mem::size_of::<T>() == layout.size() {
+                let new_layout = Layout::array::<T>(i * 2).unwrap();
+                ptr = unsafe { alloc.grow(ptr.cast(), layout, new_layout) }
+                    .unwrap()
+                    .cast();
+                layout = new_layout;
             }
+            assert_eq!(ptr.as_ptr() as usize % layout.align(), 0);
+            unsafe { ptr.as_ptr().add(i).write(make(i)) };
+        }
+        for i in 0..count {
+            check(unsafe { &*ptr.as_ptr().add(i) }, i);
         }
+        unsafe { alloc.deallocate(ptr.cast(), layout) };
     }
 
     #[test]
     fn basic_vec() {
-        let alloc = unsafe { RSBMalloc::new(0) };
-        let mut v1 = Vec::new_in(&alloc);
-        for i in 0..10_000 {
-            v1.push(i);
-        }
-        let mut v2 = Vec::new_in(&alloc);
-        for _ in 0..10_000 {
-            v2.push(Big::new());
-        }
-        drop(v1);
-        drop(v2);
+        let alloc = unsafe { RSBMalloc::new(0, None, Options::default()) };
+        push_all(&alloc, 10_000, |i| i, |&v, i| assert_eq!(v, i));
+        push_all(&alloc, 10_000, |_| Big::new(), |_, _| ());
         unsafe {
             alloc.free_all();
         }
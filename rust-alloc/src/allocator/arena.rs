@@ -0,0 +1,177 @@
+//! A contiguous region of address space reserved for a label
+//!
+//! The whole region is mapped `PROT_NONE` under the label's key up front,
+//! and pages are committed from it as the label needs them.  Every mapping
+//! of the label then lies within one range.
+
+use core::ptr;
+
+use spin::Mutex;
+
+use super::meta::MetaMap;
+use crate::pkey::pkey_mprotect;
+
+pub(crate) struct Arena {
+    base: usize,
+    size: usize,
+    /// Uncommitted ranges, address to length, coalesced with neighbours
+    free: Mutex<MetaMap<usize, usize>>,
+}
+
+impl Arena {
+    /// Reserve `size` bytes of address space aligned to `align`, a power of
+    /// two multiple of the page size
+    pub(crate) unsafe fn reserve(size: usize, align: usize, pkey: libc::c_int) -> Option<Self> {
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            size.checked_add(align)?,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return None;
+        }
+        let head = (align - addr as usize % align) % align;
+        if head != 0 {
+            libc::munmap(addr, head);
+        }
+        let base = addr as usize + head;
+        libc::munmap((base + size) as _, align - head);
+        if pkey_mprotect(base as _, size, libc::PROT_NONE, pkey) != 0 {
+            libc::munmap(base as _, size);
+            return None;
+        }
+        let mut free = MetaMap::new();
+        free.insert(base, size);
+        Some(Self {
+            base,
+            size,
+            free: Mutex::new(free),
+        })
+    }
+
+    pub(crate) fn base(&self) -> *mut u8 {
+        self.base as _
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn contains(&self, ptr: *const u8) -> bool {
+        (ptr as usize).wrapping_sub(self.base) < self.size
+    }
+
+    /// Commit `size` bytes aligned to `align`, returning null if the arena
+    /// has no room left or the kernel won't commit the memory
+    ///
+    /// The reservation is made with `MAP_NORESERVE`, so isn't charged
+    /// against the commit limit.  Committing maps fresh pages over it,
+    /// which are, so running out of memory fails here rather than as a
+    /// fault on first touch.
+    pub(crate) unsafe fn commit(&self, size: usize, align: usize, pkey: libc::c_int) -> *mut u8 {
+        let mut free = self.free.lock();
+        let found = free.iter().find_map(|&(start, len)| {
+            let aligned = (start + align - 1) & !(align - 1);
+            (aligned + size <= start + len).then_some((start, len, aligned))
+        });
+        let Some((start, len, aligned)) = found else {
+            return ptr::null_mut();
+        };
+        free.remove(&start);
+        if aligned > start {
+            free.insert(start, aligned - start);
+        }
+        if aligned + size < start + len {
+            free.insert(aligned + size, start + len - aligned - size);
+        }
+        drop(free);
+        let addr = libc::mmap(
+            aligned as _,
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED
+            || pkey_mprotect(addr, size, libc::PROT_READ | libc::PROT_WRITE, pkey) != 0
+        {
+            self.decommit(aligned as _, size, pkey);
+            return ptr::null_mut();
+        }
+        aligned as _
+    }
+
+    /// Drop the pages at `ptr` and return them to the arena
+    pub(crate) unsafe fn decommit(&self, ptr: *mut u8, size: usize, pkey: libc::c_int) {
+        // Map the range back to an uncharged reservation, which also drops
+        // its pages
+        let addr = libc::mmap(
+            ptr as _,
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            libc::madvise(ptr as _, size, libc::MADV_DONTNEED);
+        }
+        pkey_mprotect(ptr as _, size, libc::PROT_NONE, pkey);
+        let mut free = self.free.lock();
+        let (mut start, mut len) = (ptr as usize, size);
+        if let Some(&(prev, prev_len)) = free.below(&start) {
+            if prev + prev_len == start {
+                free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = free.remove(&(start + len)) {
+            len += next_len;
+        }
+        free.insert(start, len);
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as _, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::allocator::page_allocator::PAGE_SIZE;
+
+    #[test]
+    fn commit_and_coalesce() {
+        let page = *PAGE_SIZE;
+        let arena = unsafe { Arena::reserve(16 * page, page, 0) }.unwrap();
+        let a = unsafe { arena.commit(4 * page, page, 0) };
+        let b = unsafe { arena.commit(4 * page, page, 0) };
+        assert!(arena.contains(a) && arena.contains(b));
+        assert!(!arena.contains(unsafe { arena.base().add(16 * page) }));
+        unsafe {
+            a.write(1);
+            b.write(2);
+        }
+
+        // Too big for what's left
+        assert!(unsafe { arena.commit(9 * page, page, 0) }.is_null());
+        unsafe {
+            arena.decommit(a, 4 * page, 0);
+            arena.decommit(b, 4 * page, 0);
+        }
+        // Freed ranges merge back into one
+        let whole = unsafe { arena.commit(16 * page, page, 0) };
+        assert_eq!(whole, arena.base());
+        assert_eq!(unsafe { whole.read() }, 0);
+    }
+}
@@ -0,0 +1,485 @@
+//! Bins of equally sized slots
+//!
+//! Each bin carves its slots out of chunks mapped from the page allocator.
+//! Freed slots are kept on a lock-free free list threaded through the first
+//! word of each slot.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::{alloc::Layout, ptr, ptr::NonNull};
+
+use spin::Mutex;
+
+use super::meta::MetaVec;
+use super::page_allocator::PageAllocator;
+use super::{corruption, protect, reveal, slot_align};
+use crate::stats::BinStats;
+
+/// Read the (unmangled) next pointer stored in the free slot at `slot`
+///
+/// A racing pop may be reading this while the slot's new owner writes to
+/// it, the compare-exchange will discard the value.
+unsafe fn next(slot: *mut u8, secret: usize) -> *mut u8 {
+    let mangled = (*(slot as *const AtomicUsize)).load(Ordering::Relaxed);
+    reveal(slot, mangled, secret) as *mut u8
+}
+
+/// Store the next pointer, mangled, into the free slot at `slot`
+unsafe fn set_next(slot: *mut u8, next: *mut u8, secret: usize) {
+    (*(slot as *const AtomicUsize)).store(protect(slot, next as usize, secret), Ordering::Relaxed);
+}
+
+/// A lock-free (Treiber) stack of free slots
+///
+/// The head pointer is tagged, in its top 16 bits, with a counter which is
+/// bumped on every update.  A pop which races with another thread popping
+/// and then pushing back the same slot (the ABA problem) therefore fails
+/// its compare-exchange rather than installing a stale next pointer.
+struct FreeList {
+    head: AtomicU64,
+}
+
+const TAG_SHIFT: u32 = 48;
+const ADDR_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+/// Whether every slot of a chunk of `size` bytes at `ptr` fits beside the
+/// tag.  Linux only maps above 2^47 when given a hint above it, which the
+/// page allocator never does, but other kernels and platforms may differ.
+fn fits_tag(ptr: *mut u8, size: usize) -> bool {
+    (ptr as u64)
+        .checked_add(size as u64)
+        .is_some_and(|end| end <= ADDR_MASK + 1)
+}
+
+impl FreeList {
+    const fn new() -> Self {
+        Self {
+            head: AtomicU64::new(0),
+        }
+    }
+
+    fn split(head: u64) -> (*mut u8, u64) {
+        ((head & ADDR_MASK) as usize as *mut u8, head >> TAG_SHIFT)
+    }
+
+    fn join(ptr: *mut u8, tag: u64) -> u64 {
+        if ptr as u64 & !ADDR_MASK != 0 {
+            corruption(format_args!("free slot {ptr:p} is too wide to tag"));
+        }
+        (tag << TAG_SHIFT) | ptr as u64
+    }
+
+    /// The slot at the top of the stack
+    fn first(&self) -> *mut u8 {
+        Self::split(self.head.load(Ordering::Acquire)).0
+    }
+
+    fn clear(&self) {
+        self.head.store(0, Ordering::Release);
+    }
+
+    /// Pop the top slot, returning it and the (unvalidated) slot below it
+    ///
+    /// # Safety
+    /// The caller must be able to read every slot in the bin, since a
+    /// racing pop means we may read the next pointer from a slot which has
+    /// just been handed out.
+    unsafe fn pop(&self, secret: usize) -> Option<(*mut u8, *mut u8)> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let (slot, tag) = Self::split(head);
+            if slot.is_null() {
+                return None;
+            }
+            let next = next(slot, secret);
+            // If we raced, `next` may be garbage which won't fit beside the
+            // tag, but then the compare-exchange fails anyway
+            let new = Self::join((next as u64 & ADDR_MASK) as usize as *mut u8, tag + 1);
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Some((slot, next)),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Push the chain of slots from `first` to `last`, which must already
+    /// be linked together
+    unsafe fn push_chain(&self, first: *mut u8, last: *mut u8, secret: usize) {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let (slot, tag) = Self::split(head);
+            set_next(last, slot, secret);
+            let new = Self::join(first, tag + 1);
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+struct Chunk {
+    ptr: *mut u8,
+    layout: Layout,
+    /// One bit per slot, set while the slot is free.  Only maintained in
+    /// hardened mode.
+    free_map: MetaVec<u64>,
+}
+
+impl Chunk {
+    fn contains(&self, ptr: *mut u8) -> bool {
+        ptr >= self.ptr && (ptr as usize) < self.ptr as usize + self.layout.size()
+    }
+}
+
+pub(crate) struct Bin {
+    slot_size: usize,
+    /// The size of each chunk mapped for the bin
+    chunk_size: usize,
+    free_head: FreeList,
+    /// The next unused slot in the current chunk, bumped atomically
+    bump: AtomicUsize,
+    /// The end of the current chunk, zero while a new one is being mapped
+    end: AtomicUsize,
+    /// Chunks mapped for this bin, sorted by address.  This lock is only
+    /// taken to map new chunks, or for hardened mode's free slot bitmaps.
+    pages: Mutex<MetaVec<Chunk>>,
+    hardened: bool,
+    /// Serialises pops from the free list.  When quarantined slots are moved
+    /// to a key we can't access, a racing pop could otherwise fault reading
+    /// the next pointer of a slot which has been allocated and quarantined.
+    pop_lock: Option<Mutex<()>>,
+    /// Mixed into the free list pointers stored within the slots
+    secret: usize,
+    live: AtomicUsize,
+    free: AtomicUsize,
+    chunks: AtomicUsize,
+}
+
+unsafe impl Send for Bin {}
+unsafe impl Sync for Bin {}
+
+impl Bin {
+    pub(crate) fn new(
+        slot_size: usize,
+        chunk_size: usize,
+        hardened: bool,
+        serialise_pops: bool,
+        secret: usize,
+    ) -> Self {
+        Self {
+            slot_size,
+            chunk_size,
+            free_head: FreeList::new(),
+            bump: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+            pages: Mutex::new(MetaVec::new()),
+            hardened,
+            pop_lock: serialise_pops.then(|| Mutex::new(())),
+            secret,
+            live: AtomicUsize::new(0),
+            free: AtomicUsize::new(0),
+            chunks: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    fn add_one(&self, pages: &PageAllocator) -> *mut u8 {
+        let slot_size = self.slot_size;
+        loop {
+            // Load the bump pointer before the end, see below for why
+            let cur = self.bump.load(Ordering::SeqCst);
+            let end = self.end.load(Ordering::SeqCst);
+            if cur != 0 && cur + slot_size <= end {
+                if self
+                    .bump
+                    .compare_exchange_weak(cur, cur + slot_size, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return cur as *mut u8;
+                }
+                continue;
+            }
+            let mut chunks = self.pages.lock();
+            if self.bump.load(Ordering::SeqCst) != cur || self.end.load(Ordering::SeqCst) != end {
+                // Someone else mapped a new chunk while we waited
+                continue;
+            }
+            unsafe {
+                let layout =
+                    Layout::from_size_align_unchecked(self.chunk_size, slot_align(slot_size));
+                let ptr = pages.alloc(layout);
+                if ptr.is_null() {
+                    return ptr::null_mut();
+                }
+                if !fits_tag(ptr, self.chunk_size) {
+                    pages.dealloc(ptr, layout);
+                    return ptr::null_mut();
+                }
+                let mut free_map = MetaVec::new();
+                if self.hardened {
+                    free_map.resize((self.chunk_size / slot_size + 63) / 64, 0);
+                }
+                let at = chunks.partition_point(|c| c.ptr < ptr);
+                chunks.insert(
+                    at,
+                    Chunk {
+                        ptr,
+                        layout,
+                        free_map,
+                    },
+                );
+                self.chunks.fetch_add(1, Ordering::Relaxed);
+                // Invalidate the end before moving the bump pointer into the
+                // new chunk, so nobody can pair the new bump pointer with the
+                // old end.  Anyone who paired the old bump pointer with the
+                // new end will fail their compare-exchange.
+                self.end.store(0, Ordering::SeqCst);
+                self.bump.store(ptr as usize + slot_size, Ordering::SeqCst);
+                self.end
+                    .store(ptr as usize + self.chunk_size, Ordering::SeqCst);
+                return ptr;
+            }
+        }
+    }
+
+    /// Find the chunk containing `ptr` and the index of the slot within it,
+    /// aborting if `ptr` is not the start of a slot in this bin.
+    fn locate(&self, chunks: &[Chunk], ptr: *mut u8) -> (usize, usize) {
+        let at = chunks.partition_point(|c| c.ptr <= ptr);
+        let chunk = match at.checked_sub(1) {
+            Some(c) if chunks[c].contains(ptr) => c,
+            _ => corruption(format_args!(
+                "invalid free of {ptr:p}, not within the {} byte bin",
+                self.slot_size
+            )),
+        };
+        let offset = ptr as usize - chunks[chunk].ptr as usize;
+        // Slots needn't divide the chunk evenly, the tail is never handed out
+        if offset % self.slot_size != 0 || offset + self.slot_size > chunks[chunk].layout.size() {
+            corruption(format_args!(
+                "invalid free of {ptr:p}, not on a slot boundary of the {} byte bin",
+                self.slot_size
+            ));
+        }
+        (chunk, offset / self.slot_size)
+    }
+
+    /// Mark the slot at `ptr` as free, aborting if it already is, or was
+    /// never handed out
+    fn mark_free(&self, ptr: *mut u8) {
+        let mut chunks = self.pages.lock();
+        let (chunk, slot) = self.locate(&chunks, ptr);
+        // Only the current chunk has slots left to hand out, those from the
+        // bump pointer on.  Whoever handed out `ptr` moved the bump pointer
+        // past it before we could see `ptr`.
+        let bump = self.bump.load(Ordering::SeqCst);
+        if (chunks[chunk].ptr as usize..=ptr as usize).contains(&bump) {
+            corruption(format_args!(
+                "invalid free of {ptr:p}, never allocated from the {} byte bin",
+                self.slot_size
+            ));
+        }
+        let word = &mut chunks[chunk].free_map[slot / 64];
+        let bit = 1 << (slot % 64);
+        if *word & bit != 0 {
+            corruption(format_args!(
+                "double free of {ptr:p} in the {} byte bin",
+                self.slot_size
+            ));
+        }
+        *word |= bit;
+    }
+
+    /// Mark the slot at `ptr`, just taken from the free list, as in use
+    fn mark_used(&self, ptr: *mut u8) {
+        let mut chunks = self.pages.lock();
+        let (chunk, slot) = self.locate(&chunks, ptr);
+        let word = &mut chunks[chunk].free_map[slot / 64];
+        let bit = 1 << (slot % 64);
+        if *word & bit == 0 {
+            corruption(format_args!(
+                "free list of the {} byte bin is corrupt at {ptr:p}",
+                self.slot_size
+            ));
+        }
+        *word &= !bit;
+    }
+
+    /// Take the head of the free list, validating the pointer to the next
+    unsafe fn pop(&self) -> Option<*mut u8> {
+        let _serialised = self.pop_lock.as_ref().map(Mutex::lock);
+        let (slot, next) = self.free_head.pop(self.secret)?;
+        if next as usize % slot_align(self.slot_size) != 0 || next as u64 & !ADDR_MASK != 0 {
+            corruption(format_args!(
+                "free list of the {} byte bin is corrupt after {slot:p}",
+                self.slot_size
+            ));
+        }
+        if self.hardened {
+            self.mark_used(slot);
+        }
+        self.free.fetch_sub(1, Ordering::Relaxed);
+        Some(slot)
+    }
+
+    /// Allocates a pointer to a slot from this bin
+    pub(crate) unsafe fn alloc(&self, pages: &PageAllocator) -> *mut u8 {
+        let slot = match self.pop() {
+            Some(slot) => slot,
+            None => self.add_one(pages),
+        };
+        if slot.is_null() {
+            return slot;
+        }
+        self.live.fetch_add(1, Ordering::Relaxed);
+        slot
+    }
+
+    /// Allocate up to `count` slots into `out`
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) unsafe fn alloc_batch(
+        &self,
+        pages: &PageAllocator,
+        out: &mut alloc::vec::Vec<*mut u8>,
+        count: usize,
+    ) {
+        let mut taken = 0;
+        while taken < count {
+            match self.pop() {
+                Some(slot) => out.push(slot),
+                None => break,
+            }
+            taken += 1;
+        }
+        while taken < count {
+            let slot = self.add_one(pages);
+            if slot.is_null() {
+                break;
+            }
+            out.push(slot);
+            taken += 1;
+        }
+        self.live.fetch_add(taken, Ordering::Relaxed);
+    }
+
+    /// Take a freed slot out of use, without yet making it available for
+    /// allocation
+    pub(crate) fn retire(&self, ptr: *mut u8) {
+        if self.hardened {
+            self.mark_free(ptr);
+        }
+        self.live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Make a retired slot available for allocation again
+    pub(crate) unsafe fn release(&self, ptr: *mut u8) {
+        self.free_head.push_chain(ptr, ptr, self.secret);
+        self.free.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) unsafe fn dealloc(&self, ptr: *mut u8) {
+        self.retire(ptr);
+        self.release(ptr);
+    }
+
+    /// Free many slots, pushing them onto the free list in one go
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    pub(crate) unsafe fn dealloc_batch(&self, ptrs: &[*mut u8]) {
+        let (Some(&first), Some(&last)) = (ptrs.first(), ptrs.last()) else {
+            return;
+        };
+        for &ptr in ptrs {
+            self.retire(ptr);
+        }
+        // Link the slots together so they can be pushed in one go
+        for pair in ptrs.windows(2) {
+            set_next(pair[0], pair[1], self.secret);
+        }
+        self.free_head.push_chain(first, last, self.secret);
+        self.free.fetch_add(ptrs.len(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> BinStats {
+        BinStats {
+            slot_size: self.slot_size,
+            live_slots: self.live.load(Ordering::Relaxed),
+            free_slots: self.free.load(Ordering::Relaxed),
+            chunks: self.chunks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Gather every slot which has been handed out and not returned to the
+    /// free list.  The slots are gathered first so that the chunk lock is
+    /// not held while the caller inspects them.
+    ///
+    /// The free list is not locked, so this is only accurate if nothing
+    /// else is allocating from or freeing to the bin.  `quarantined` must be
+    /// sorted.
+    unsafe fn live_slots(&self, quarantined: &[*mut u8]) -> MetaVec<*mut u8> {
+        let ps = self.pages.lock();
+        let mut free = MetaVec::new();
+        let mut cur = self.free_head.first();
+        while !cur.is_null() {
+            free.push(cur);
+            cur = next(cur, self.secret);
+        }
+        free.sort_unstable();
+        let slot_size = self.slot_size;
+        let mut live = MetaVec::new();
+        let bump = self.bump.load(Ordering::SeqCst) as *mut u8;
+        let current_end = self.end.load(Ordering::SeqCst) as *mut u8;
+        for chunk in ps.iter() {
+            let chunk_end = chunk.ptr.add(chunk.layout.size());
+            // Only the chunk we're bumping through has unused slots
+            let end = if chunk_end == current_end {
+                bump
+            } else {
+                chunk_end
+            };
+            let mut slot = chunk.ptr;
+            while slot.add(slot_size) <= end {
+                if free.binary_search(&slot).is_err() && quarantined.binary_search(&slot).is_err() {
+                    live.push(slot);
+                }
+                slot = slot.add(slot_size);
+            }
+        }
+        live
+    }
+
+    pub(crate) unsafe fn walk(
+        &self,
+        func: &mut dyn FnMut(NonNull<u8>, usize),
+        quarantined: &[*mut u8],
+    ) {
+        for &slot in &self.live_slots(quarantined) {
+            func(NonNull::new_unchecked(slot), self.slot_size);
+        }
+    }
+
+    pub(crate) fn free_all(&self, pages: &PageAllocator) {
+        // This is part of Drop so nothing else should be using the bin
+        let mut ps = self.pages.lock();
+        self.free_head.clear();
+        self.end.store(0, Ordering::SeqCst);
+        self.bump.store(0, Ordering::SeqCst);
+        self.live.store(0, Ordering::Relaxed);
+        self.free.store(0, Ordering::Relaxed);
+        self.chunks.store(0, Ordering::Relaxed);
+        for chunk in &*ps {
+            unsafe {
+                pages.dealloc(chunk.ptr, chunk.layout);
+            }
+        }
+        ps.clear();
+    }
+}
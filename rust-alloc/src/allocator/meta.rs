@@ -0,0 +1,510 @@
+//! Allocator for the allocator's own bookkeeping
+//!
+//! When a label serves as the global allocator, any `Vec` or map kept by
+//! the allocator would be allocated from the label itself, recursing into
+//! locks it already holds.  Bookkeeping is allocated here instead, straight
+//! from `mmap`.  It is left under the default key, so stays accessible
+//! whatever the level of the label it describes.
+//!
+//! Collections can't be given a custom allocator on stable, so this also
+//! provides the few the allocator needs.
+
+use alloc::alloc::handle_alloc_error;
+use core::alloc::Layout;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{mem, slice};
+
+use spin::Mutex;
+
+use super::page_allocator::PAGE_SIZE;
+
+/// Size classes run in powers of two from `MIN_CLASS` to `MAX_CLASS`,
+/// anything larger is mapped directly
+const MIN_CLASS: usize = 16;
+const MAX_CLASS: usize = 2048;
+const CLASSES: usize = 8;
+const CHUNK_SIZE: usize = 0x10000;
+
+#[derive(Clone, Copy)]
+struct Class {
+    /// Head of the free list, threaded through the first word of each slot
+    free: usize,
+    /// The next unused slot of the current chunk, and its end
+    bump: usize,
+    end: usize,
+}
+
+/// Bookkeeping is small and rarely changes, so one lock is plenty
+static CLASS_STATE: Mutex<[Class; CLASSES]> = Mutex::new(
+    [Class {
+        free: 0,
+        bump: 0,
+        end: 0,
+    }; CLASSES],
+);
+
+/// The size class serving `layout`, or `None` if it must be mapped directly
+fn class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_CLASS);
+    (size <= MAX_CLASS)
+        .then(|| (size.next_power_of_two().trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize)
+}
+
+fn mapping_size(layout: Layout) -> usize {
+    let page = *PAGE_SIZE;
+    (layout.size() + page - 1) / page * page
+}
+
+unsafe fn map(size: usize) -> *mut u8 {
+    let addr = libc::mmap(
+        ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if addr == libc::MAP_FAILED {
+        ptr::null_mut()
+    } else {
+        addr as _
+    }
+}
+
+/// Allocate bookkeeping memory for `layout`, returning null on failure.
+/// Alignment beyond the page size isn't supported.
+fn allocate(layout: Layout) -> *mut u8 {
+    if layout.align() > *PAGE_SIZE {
+        return ptr::null_mut();
+    }
+    let Some(index) = class(layout) else {
+        return unsafe { map(mapping_size(layout)) };
+    };
+    let slot_size = MIN_CLASS << index;
+    let mut classes = CLASS_STATE.lock();
+    let class = &mut classes[index];
+    if class.free != 0 {
+        let slot = class.free;
+        class.free = unsafe { *(slot as *const usize) };
+        return slot as _;
+    }
+    if class.bump + slot_size > class.end {
+        let chunk = unsafe { map(CHUNK_SIZE) };
+        if chunk.is_null() {
+            return chunk;
+        }
+        // Whatever was left of the old chunk is abandoned
+        class.bump = chunk as usize;
+        class.end = chunk as usize + CHUNK_SIZE;
+    }
+    let slot = class.bump;
+    class.bump += slot_size;
+    slot as _
+}
+
+unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    let Some(index) = class(layout) else {
+        libc::munmap(ptr as _, mapping_size(layout));
+        return;
+    };
+    let mut classes = CLASS_STATE.lock();
+    let class = &mut classes[index];
+    *(ptr as *mut usize) = class.free;
+    class.free = ptr as usize;
+}
+
+/// A growable array in bookkeeping memory, with just what the allocator
+/// needs of `Vec`
+pub(crate) struct MetaVec<T> {
+    ptr: NonNull<T>,
+    cap: usize,
+    len: usize,
+}
+
+unsafe impl<T: Send> Send for MetaVec<T> {}
+unsafe impl<T: Sync> Sync for MetaVec<T> {}
+
+impl<T> MetaVec<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            cap: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        let mut vec = Self::new();
+        vec.reserve(capacity);
+        vec
+    }
+
+    /// Make room for at least `additional` more elements
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("capacity overflow");
+        if needed <= self.cap || mem::size_of::<T>() == 0 {
+            return;
+        }
+        let cap = needed.max(self.cap * 2).max(4);
+        let layout = Layout::array::<T>(cap).expect("capacity overflow");
+        let ptr = allocate(layout) as *mut T;
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(self.ptr.as_ptr(), ptr, self.len);
+            self.free_buffer();
+            self.ptr = NonNull::new_unchecked(ptr);
+        }
+        self.cap = cap;
+    }
+
+    unsafe fn free_buffer(&mut self) {
+        if self.cap != 0 && mem::size_of::<T>() != 0 {
+            let layout = Layout::array::<T>(self.cap).unwrap_unchecked();
+            deallocate(self.ptr.as_ptr() as _, layout);
+        }
+    }
+
+    pub(crate) fn push(&mut self, value: T) {
+        self.reserve(1);
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+    }
+
+    pub(crate) fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len, "insertion index out of bounds");
+        self.reserve(1);
+        unsafe {
+            let at = self.ptr.as_ptr().add(index);
+            ptr::copy(at, at.add(1), self.len - index);
+            at.write(value);
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index out of bounds");
+        self.len -= 1;
+        unsafe {
+            let at = self.ptr.as_ptr().add(index);
+            let value = at.read();
+            ptr::copy(at.add(1), at, self.len - index);
+            value
+        }
+    }
+
+    /// Drop the first `count` elements, moving the rest down
+    pub(crate) fn remove_front(&mut self, count: usize) {
+        let count = count.min(self.len);
+        let base = self.ptr.as_ptr();
+        // Shorten first, so a panicking drop leaks rather than double drops
+        let len = mem::replace(&mut self.len, 0);
+        unsafe {
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(base, count));
+            ptr::copy(base.add(count), base, len - count);
+        }
+        self.len = len - count;
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            let tail = unsafe { self.ptr.as_ptr().add(len) };
+            let tail = ptr::slice_from_raw_parts_mut(tail, self.len - len);
+            self.len = len;
+            unsafe { ptr::drop_in_place(tail) };
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub(crate) fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut index = 0;
+        while index < self.len {
+            if keep(&self[index]) {
+                index += 1;
+            } else {
+                drop(self.remove(index));
+            }
+        }
+    }
+
+    pub(crate) fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl<T: Clone> MetaVec<T> {
+    pub(crate) fn resize(&mut self, len: usize, value: T) {
+        if len > self.len {
+            self.reserve(len - self.len);
+            while self.len < len {
+                self.push(value.clone());
+            }
+        } else {
+            self.truncate(len);
+        }
+    }
+}
+
+impl<T> Default for MetaVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for MetaVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for MetaVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a MetaVec<T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T> Drop for MetaVec<T> {
+    fn drop(&mut self) {
+        self.clear();
+        unsafe { self.free_buffer() };
+    }
+}
+
+/// An ordered map kept as a sorted [`MetaVec`]
+///
+/// Bookkeeping maps are small or rarely updated, so a binary search over a
+/// flat array serves in place of a `BTreeMap`.
+pub(crate) struct MetaMap<K, V> {
+    entries: MetaVec<(K, V)>,
+}
+
+impl<K: Ord + Copy, V> MetaMap<K, V> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: MetaVec::new(),
+        }
+    }
+
+    fn search(&self, key: &K) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.search(key).ok().map(|index| &self.entries[index].1)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.search(&key) {
+            Ok(index) => Some(mem::replace(&mut self.entries[index].1, value)),
+            Err(index) => {
+                self.entries.insert(index, (key, value));
+                None
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.search(key).ok()?;
+        Some(self.entries.remove(index).1)
+    }
+
+    /// The entry with the greatest key below `key`
+    pub(crate) fn below(&self, key: &K) -> Option<&(K, V)> {
+        let index = self.search(key).unwrap_or_else(|index| index);
+        index.checked_sub(1).map(|index| &self.entries[index])
+    }
+
+    /// The entry with the greatest key no greater than `key`
+    pub(crate) fn at_or_below(&self, key: &K) -> Option<&(K, V)> {
+        match self.search(key) {
+            Ok(index) => Some(&self.entries[index]),
+            Err(index) => index.checked_sub(1).map(|index| &self.entries[index]),
+        }
+    }
+
+    /// The entries with keys from `start` up to, but not including, `end`
+    pub(crate) fn range(&self, start: &K, end: &K) -> &[(K, V)] {
+        let from = self.search(start).unwrap_or_else(|index| index);
+        let to = self.search(end).unwrap_or_else(|index| index);
+        &self.entries[from..to.max(from)]
+    }
+
+    pub(crate) fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.entries.retain(|(k, v)| keep(k, v));
+    }
+
+    /// Entries in ascending order of key
+    pub(crate) fn iter(&self) -> slice::Iter<'_, (K, V)> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        assert_eq!(class(layout(0, 1)), Some(0));
+        assert_eq!(class(layout(16, 8)), Some(0));
+        assert_eq!(class(layout(17, 8)), Some(1));
+        assert_eq!(class(layout(8, 64)), Some(2));
+        assert_eq!(class(layout(2048, 8)), Some(CLASSES - 1));
+        assert_eq!(class(layout(2049, 8)), None);
+    }
+
+    #[test]
+    fn collections() {
+        let mut map = MetaMap::new();
+        let mut big = MetaVec::new();
+        for i in (0..10_000u64).rev() {
+            map.insert(i, i * 2);
+            big.push(i);
+        }
+        map.retain(|k, _| k % 3 == 0);
+        assert_eq!(map.iter().len(), 3334);
+        assert!(map.iter().all(|&(k, v)| v == k * 2));
+        assert_eq!(map.below(&3), Some(&(0, 0)));
+        assert_eq!(map.at_or_below(&3), Some(&(3, 6)));
+        assert_eq!(map.range(&1, &10).len(), 3);
+        assert_eq!(big.iter().sum::<u64>(), 9999 * 5000);
+
+        // Elements moved around must neither leak nor be dropped twice
+        let mut names = MetaVec::new();
+        names.extend((0..100).map(|i| i.to_string()));
+        names.remove_front(40);
+        assert_eq!(names.remove(0), "40");
+        names.retain(|name| name.len() == 2);
+        assert_eq!(names.len(), 59);
+    }
+
+    #[test]
+    fn growth() {
+        // Grows from the size classes into direct mappings, keeping its
+        // elements in place
+        let mut vec = MetaVec::new();
+        let mut caps = MetaVec::new();
+        for i in 0..5000u32 {
+            vec.push(i);
+            if caps.last() != Some(&vec.cap) {
+                caps.push(vec.cap);
+            }
+        }
+        assert_eq!(
+            &caps[..],
+            [4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192]
+        );
+        assert!(vec.iter().copied().eq(0..5000));
+
+        vec.insert(0, 5000);
+        vec.insert(2500, 5001);
+        assert_eq!(
+            (vec[0], vec[1], vec[2500], vec.len()),
+            (5000, 0, 5001, 5002)
+        );
+        vec.truncate(10);
+        vec.resize(12, 7);
+        assert_eq!(&vec[8..], [7, 8, 7, 7]);
+
+        let vec = MetaVec::<u64>::with_capacity(100);
+        assert!(vec.cap >= 100 && vec.is_empty());
+    }
+
+    #[test]
+    fn removal() {
+        let mut vec = MetaVec::new();
+        vec.extend(0..10);
+        assert_eq!(vec.remove(9), 9);
+        assert_eq!(vec.remove(0), 0);
+        assert_eq!(&vec[..], [1, 2, 3, 4, 5, 6, 7, 8]);
+        vec.remove_front(3);
+        assert_eq!(&vec[..], [4, 5, 6, 7, 8]);
+        vec.remove_front(10);
+        assert!(vec.is_empty());
+
+        let mut map = MetaMap::new();
+        assert_eq!(map.insert(2, "two"), None);
+        assert_eq!(map.insert(2, "deux"), Some("two"));
+        assert_eq!(map.remove(&1), None);
+        assert_eq!(map.remove(&2), Some("deux"));
+        assert_eq!(map.get(&2), None);
+    }
+
+    #[test]
+    fn lookups() {
+        let mut map = MetaMap::new();
+        for key in [10, 20, 30] {
+            map.insert(key, key / 10);
+        }
+        assert_eq!(map.below(&10), None);
+        assert_eq!(map.below(&11), Some(&(10, 1)));
+        assert_eq!(map.below(&30), Some(&(20, 2)));
+        assert_eq!(map.below(&99), Some(&(30, 3)));
+        assert_eq!(map.at_or_below(&9), None);
+        assert_eq!(map.at_or_below(&10), Some(&(10, 1)));
+        assert_eq!(map.at_or_below(&29), Some(&(20, 2)));
+        assert_eq!(map.range(&10, &30), [(10, 1), (20, 2)]);
+        assert_eq!(map.range(&11, &31), [(20, 2), (30, 3)]);
+        assert_eq!(map.range(&0, &10), []);
+        assert_eq!(map.range(&30, &10), []);
+        assert_eq!(MetaMap::<u32, u32>::new().at_or_below(&0), None);
+    }
+
+    #[test]
+    fn drops() {
+        use std::rc::Rc;
+
+        let counted = Rc::new(());
+        let mut vec = MetaVec::new();
+        vec.resize(20, Rc::clone(&counted));
+        assert_eq!(Rc::strong_count(&counted), 21);
+        drop(vec.remove(0));
+        vec.remove_front(4);
+        vec.truncate(10);
+        vec.retain(|_| false);
+        assert_eq!(Rc::strong_count(&counted), 1);
+
+        let mut map = MetaMap::new();
+        for key in 0..10 {
+            map.insert(key, Rc::clone(&counted));
+        }
+        map.insert(0, Rc::clone(&counted));
+        map.remove(&1);
+        map.retain(|k, _| k % 2 == 0);
+        assert_eq!(Rc::strong_count(&counted), 6);
+        drop(map);
+        vec.extend((0..3).map(|_| Rc::clone(&counted)));
+        drop(vec);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+}
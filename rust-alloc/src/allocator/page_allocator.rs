@@ -1,11 +1,16 @@
 use core::{
     alloc::Layout,
     cmp::{max, min},
-    ptr,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use lazy_static::lazy_static;
+use spin::Mutex;
 
+use super::arena::Arena;
+use super::meta::{MetaMap, MetaVec};
 use crate::pkey::pkey_mprotect;
+use crate::registry::{self, LabelId};
 
 lazy_static! {
     pub static ref PAGE_SIZE: usize = page_size();
@@ -15,13 +20,252 @@ fn page_size() -> usize {
     unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
 }
 
+/// The size of the huge pages we ask for
+pub(crate) const HUGE_PAGE_SIZE: usize = 0x200000;
+
+/// Whether a label's mappings are backed by huge pages
+///
+/// Only mappings of at least 2 MiB are affected, these are rounded up to
+/// a multiple of 2 MiB and aligned to match.  With huge pages enabled,
+/// bins map their chunks in 2 MiB multiples too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    /// Only use normal pages
+    #[default]
+    Never,
+    /// Advise the kernel to back mappings with transparent huge pages.
+    /// The kernel may still use normal pages, or split huge pages later.
+    Transparent,
+    /// Map huge pages from the hugetlbfs pool, falling back to
+    /// [`HugePages::Transparent`] when the pool is exhausted.  Quarantined
+    /// slots within a hugetlbfs mapping can't be moved to another key.
+    HugeTlb,
+}
+
+/// A mapping made with huge pages enabled
+#[derive(Debug, Clone, Copy)]
+struct HugeMapping {
+    size: usize,
+    /// How the mapping got huge pages: [`HugePages::HugeTlb`] if it came
+    /// from the pool, [`HugePages::Transparent`] if the kernel took the
+    /// advice, or [`HugePages::Never`] if it refused it
+    kind: HugePages,
+}
+
+/// Map `size` bytes aligned to `align`, by over-mapping and trimming
+unsafe fn map_aligned(size: usize, align: usize) -> *mut libc::c_void {
+    let addr = libc::mmap(
+        ptr::null_mut(),
+        size + align,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if addr == libc::MAP_FAILED {
+        return addr;
+    }
+    let head = (align - addr as usize % align) % align;
+    if head != 0 {
+        libc::munmap(addr, head);
+    }
+    let tail = align - head;
+    libc::munmap((addr as *mut u8).add(head + size) as _, tail);
+    (addr as *mut u8).add(head) as _
+}
+
 pub struct PageAllocator {
     pkey: libc::c_int,
+    /// The label recorded as owning our mappings in the registry
+    owner: LabelId,
+    mapped_bytes: AtomicUsize,
+    large_allocations: AtomicUsize,
+    large_bytes: AtomicUsize,
+    /// Outstanding large allocations, keyed by address, when tracking
+    large: Option<Mutex<MetaMap<usize, Layout>>>,
+    huge_pages: HugePages,
+    hugetlb_bytes: AtomicUsize,
+    transparent_huge_page_bytes: AtomicUsize,
+    /// Mappings rounded for huge pages, keyed by address, since their size
+    /// can't be recovered from the layout if the mapping was reallocated
+    huge: Option<Mutex<MetaMap<usize, HugeMapping>>>,
+    /// The region every mapping is committed from, if one was reserved
+    arena: Option<Arena>,
 }
 
 impl PageAllocator {
-    pub(crate) const fn new(pkey: libc::c_int) -> Self {
-        Self { pkey }
+    pub(crate) const fn new(
+        pkey: libc::c_int,
+        owner: LabelId,
+        track_allocations: bool,
+        huge_pages: HugePages,
+    ) -> Self {
+        Self {
+            pkey,
+            owner,
+            mapped_bytes: AtomicUsize::new(0),
+            large_allocations: AtomicUsize::new(0),
+            large_bytes: AtomicUsize::new(0),
+            large: if track_allocations {
+                Some(Mutex::new(MetaMap::new()))
+            } else {
+                None
+            },
+            huge_pages,
+            hugetlb_bytes: AtomicUsize::new(0),
+            transparent_huge_page_bytes: AtomicUsize::new(0),
+            huge: match huge_pages {
+                HugePages::Never => None,
+                _ => Some(Mutex::new(MetaMap::new())),
+            },
+            arena: None,
+        }
+    }
+
+    /// Reserve an arena of at least `size` bytes to commit every later
+    /// mapping from, returning false if the address space isn't available
+    pub(crate) unsafe fn reserve(&mut self, size: usize) -> bool {
+        let granule = match self.huge_pages {
+            HugePages::Never => *PAGE_SIZE,
+            _ => HUGE_PAGE_SIZE,
+        };
+        let Some(size) = size.max(1).checked_add(granule - 1) else {
+            return false;
+        };
+        let size = size / granule * granule;
+        self.arena = Arena::reserve(size, granule, self.pkey);
+        match &self.arena {
+            // The whole arena belongs to us, committed or not
+            Some(arena) => registry::insert(arena.base(), arena.size(), self.owner),
+            None => return false,
+        }
+        true
+    }
+
+    pub(crate) fn arena(&self) -> Option<&Arena> {
+        self.arena.as_ref()
+    }
+
+    /// Map `size` bytes aligned to `align` for the label, from the arena if
+    /// there is one
+    ///
+    /// Never passes an address hint, so Linux keeps every mapping below
+    /// 2^47, which bins rely on to tag their free lists.
+    unsafe fn map(&self, size: usize, align: usize) -> *mut libc::c_void {
+        if let Some(arena) = &self.arena {
+            let addr = arena.commit(size, align, self.pkey);
+            return if addr.is_null() {
+                libc::MAP_FAILED
+            } else {
+                addr as _
+            };
+        }
+        let addr = if align > *PAGE_SIZE {
+            map_aligned(size, align)
+        } else {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr != libc::MAP_FAILED && !self.protect(addr, size) {
+            return libc::MAP_FAILED;
+        }
+        addr
+    }
+
+    /// Put a fresh mapping under the label's key and record it, or unmap it
+    /// if it can't be, since the label's levels wouldn't apply to it
+    unsafe fn protect(&self, addr: *mut libc::c_void, size: usize) -> bool {
+        if pkey_mprotect(addr, size, libc::PROT_READ | libc::PROT_WRITE, self.pkey) != 0 {
+            libc::munmap(addr, size);
+            return false;
+        }
+        registry::insert(addr as _, size, self.owner);
+        true
+    }
+
+    /// Undo [`Self::map`], returning whether the pages were released
+    unsafe fn unmap(&self, ptr: *mut u8, size: usize) -> bool {
+        match &self.arena {
+            Some(arena) => {
+                arena.decommit(ptr, size, self.pkey);
+                true
+            }
+            None => {
+                registry::remove(ptr, size);
+                libc::munmap(ptr as _, size) == 0
+            }
+        }
+    }
+
+    /// Whether mappings of `size` bytes use huge pages
+    fn is_huge(&self, size: usize) -> bool {
+        self.huge.is_some() && size >= HUGE_PAGE_SIZE
+    }
+
+    pub(crate) fn pkey(&self) -> libc::c_int {
+        self.pkey
+    }
+
+    /// Move the pages at `ptr` to a different protection key
+    pub(crate) unsafe fn rekey(&self, ptr: *mut u8, len: usize, pkey: libc::c_int) {
+        pkey_mprotect(ptr as _, len, libc::PROT_READ | libc::PROT_WRITE, pkey);
+    }
+
+    pub(crate) fn tracks_allocations(&self) -> bool {
+        self.large.is_some()
+    }
+
+    /// Call `func` for every tracked large allocation
+    pub(crate) fn walk_large(&self, func: &mut dyn FnMut(NonNull<u8>, usize)) {
+        if let Some(large) = &self.large {
+            let mut sizes = MetaVec::new();
+            sizes.extend(
+                large
+                    .lock()
+                    .iter()
+                    .map(|(addr, layout)| (*addr, layout.size())),
+            );
+            for &(addr, size) in &sizes {
+                if let Some(ptr) = NonNull::new(addr as *mut u8) {
+                    func(ptr, size);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn mapped_bytes(&self) -> usize {
+        self.mapped_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn large_allocations(&self) -> usize {
+        self.large_allocations.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn large_bytes(&self) -> usize {
+        self.large_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn hugetlb_bytes(&self) -> usize {
+        self.hugetlb_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn transparent_huge_page_bytes(&self) -> usize {
+        self.transparent_huge_page_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The counter of bytes mapped with huge pages of `kind`, if any
+    fn huge_page_counter(&self, kind: HugePages) -> Option<&AtomicUsize> {
+        match kind {
+            HugePages::Never => None,
+            HugePages::Transparent => Some(&self.transparent_huge_page_bytes),
+            HugePages::HugeTlb => Some(&self.hugetlb_bytes),
+        }
     }
 
     pub(crate) unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -29,27 +273,90 @@ impl PageAllocator {
             Ok(l) => l.pad_to_align(),
             Err(_) => return ptr::null_mut(),
         };
-        let addr = libc::mmap(
-            ptr::null_mut(),
-            aligned_layout.size(),
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-            -1,
-            0,
-        );
-        pkey_mprotect(
-            addr,
-            aligned_layout.size(),
-            libc::PROT_READ | libc::PROT_WRITE,
-            self.pkey,
-        );
+        if self.is_huge(aligned_layout.size()) {
+            return self.alloc_huge(aligned_layout.size());
+        }
+        let addr = self.map(aligned_layout.size(), aligned_layout.align());
+        if addr == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        self.mapped_bytes
+            .fetch_add(aligned_layout.size(), Ordering::Relaxed);
+        addr as _
+    }
+
+    /// Map `size` bytes, rounded up to and aligned to whole huge pages
+    unsafe fn alloc_huge(&self, size: usize) -> *mut u8 {
+        let size = (size + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        let mut addr = libc::MAP_FAILED;
+        // The arena is reserved with normal pages, so can't use the pool
+        if self.huge_pages == HugePages::HugeTlb && self.arena.is_none() {
+            addr = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+                -1,
+                0,
+            );
+            // Protect the whole range, so huge pages aren't split by
+            // differing keys
+            if addr != libc::MAP_FAILED && !self.protect(addr, size) {
+                addr = libc::MAP_FAILED;
+            }
+        }
+        let kind = if addr != libc::MAP_FAILED {
+            HugePages::HugeTlb
+        } else {
+            addr = self.map(size, HUGE_PAGE_SIZE);
+            if addr == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+            if libc::madvise(addr, size, libc::MADV_HUGEPAGE) == 0 {
+                HugePages::Transparent
+            } else {
+                HugePages::Never
+            }
+        };
+        self.mapped_bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(counter) = self.huge_page_counter(kind) {
+            counter.fetch_add(size, Ordering::Relaxed);
+        }
+        if let Some(huge) = &self.huge {
+            huge.lock()
+                .insert(addr as usize, HugeMapping { size, kind });
+        }
         addr as _
     }
 
+    /// Unmap `ptr` if it was mapped by [`Self::alloc_huge`]
+    unsafe fn dealloc_huge(&self, ptr: *mut u8) -> bool {
+        let Some(mapping) = self
+            .huge
+            .as_ref()
+            .and_then(|huge| huge.lock().remove(&(ptr as usize)))
+        else {
+            return false;
+        };
+        if self.unmap(ptr, mapping.size) {
+            self.mapped_bytes.fetch_sub(mapping.size, Ordering::Relaxed);
+            if let Some(counter) = self.huge_page_counter(mapping.kind) {
+                counter.fetch_sub(mapping.size, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
     /// Silently fails on errors
     pub(crate) unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.dealloc_huge(ptr) {
+            return;
+        }
         if let Ok(aligned) = layout.align_to(max(layout.align(), *PAGE_SIZE)) {
-            libc::munmap(ptr as _, aligned.pad_to_align().size());
+            let size = aligned.pad_to_align().size();
+            if self.unmap(ptr, size) {
+                self.mapped_bytes.fetch_sub(size, Ordering::Relaxed);
+            }
         }
     }
 
@@ -65,43 +372,119 @@ impl PageAllocator {
         };
         let copy_len = min(layout.size(), new_size);
 
+        let huge = self
+            .huge
+            .as_ref()
+            .and_then(|huge| huge.lock().get(&(ptr as usize)).copied());
+        let grows = aligned_layout.size() > old_aligned_size.size();
+        if huge.is_some() || self.is_huge(aligned_layout.size()) || self.arena.is_some() && grows {
+            // Huge mappings can't be extended or trimmed a page at a time,
+            // and the arena may not have room beyond the mapping
+            let rounded =
+                (aligned_layout.size() + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+            if huge.is_some_and(|huge| huge.size == rounded) {
+                return ptr;
+            }
+            let new_addr = self.alloc(aligned_layout);
+            if new_addr.is_null() {
+                return new_addr;
+            }
+            ptr::copy_nonoverlapping(ptr, new_addr, copy_len);
+            self.dealloc(ptr, layout);
+            return new_addr;
+        }
+
         let old_addr_end = ptr.add(old_aligned_size.size());
         if new_size <= old_aligned_size.size() {
             let new_addr_end = ptr.add(aligned_layout.size());
             if old_addr_end > new_addr_end {
-                libc::munmap(
-                    new_addr_end as _,
-                    old_aligned_size.size() - aligned_layout.size(),
-                );
+                let trimmed = old_aligned_size.size() - aligned_layout.size();
+                if self.unmap(new_addr_end, trimmed) {
+                    self.mapped_bytes.fetch_sub(trimmed, Ordering::Relaxed);
+                }
             }
             ptr
         } else {
+            let extra = aligned_layout.size() - old_aligned_size.size();
             let appended_addr = libc::mmap(
                 old_addr_end as _,
-                aligned_layout.size() - old_aligned_size.size(),
+                extra,
                 libc::PROT_READ | libc::PROT_WRITE,
                 libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
                 -1,
                 0,
             ) as *mut u8;
-            if appended_addr == old_addr_end {
-                pkey_mprotect(
-                    appended_addr as _,
-                    aligned_layout.size() - old_aligned_size.size(),
-                    libc::PROT_READ | libc::PROT_WRITE,
-                    self.pkey,
-                );
+            if appended_addr == old_addr_end && self.protect(appended_addr as _, extra) {
+                self.mapped_bytes.fetch_add(extra, Ordering::Relaxed);
                 ptr
             } else {
-                libc::munmap(
-                    appended_addr as _,
-                    aligned_layout.size() - old_aligned_size.size(),
-                );
+                if appended_addr as *mut libc::c_void != libc::MAP_FAILED
+                    && appended_addr != old_addr_end
+                {
+                    libc::munmap(appended_addr as _, extra);
+                }
                 let new_addr = self.alloc(aligned_layout);
+                if new_addr.is_null() {
+                    return new_addr;
+                }
                 ptr::copy_nonoverlapping(ptr, new_addr, copy_len);
-                libc::munmap(ptr as _, old_aligned_size.size());
+                if self.unmap(ptr, old_aligned_size.size()) {
+                    self.mapped_bytes
+                        .fetch_sub(old_aligned_size.size(), Ordering::Relaxed);
+                }
                 new_addr
             }
         }
     }
+
+    /// Allocate memory which is too large for any bin, counting it in the
+    /// large allocation statistics
+    pub(crate) unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            self.large_allocations.fetch_add(1, Ordering::Relaxed);
+            self.large_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+            if let Some(large) = &self.large {
+                large.lock().insert(ptr as usize, layout);
+            }
+        }
+        ptr
+    }
+
+    pub(crate) unsafe fn dealloc_large(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(large) = &self.large {
+            large.lock().remove(&(ptr as usize));
+        }
+        self.dealloc(ptr, layout);
+        self.large_allocations.fetch_sub(1, Ordering::Relaxed);
+        self.large_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    pub(crate) unsafe fn realloc_large(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_ptr = self.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.large_bytes.fetch_add(new_size, Ordering::Relaxed);
+            self.large_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+            if let Some(large) = &self.large {
+                let mut large = large.lock();
+                large.remove(&(ptr as usize));
+                let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+                large.insert(new_ptr as usize, new_layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+impl Drop for PageAllocator {
+    fn drop(&mut self) {
+        // Anything still mapped is leaked, or unmapped with the arena, so
+        // must not be attributed to us once our id is gone
+        registry::remove_owner(self.owner);
+    }
 }
@@ -0,0 +1,125 @@
+//! Quarantine for freed slots
+//!
+//! Rather than going straight back onto a bin's free list, where the next
+//! allocation of that size would be handed it, freed slots wait in a FIFO
+//! until enough further frees have happened.  This makes it much harder to
+//! get a use-after-free to land on a freshly reallocated object.
+
+use spin::Mutex;
+
+use super::meta::MetaVec;
+
+/// Configuration of a label's quarantine for freed slots
+///
+/// A slot leaves the quarantine once more than `slots` slots are queued
+/// behind it, or the quarantine holds more than `bytes` bytes, whichever
+/// comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quarantine {
+    /// The maximum number of slots held in quarantine
+    pub slots: usize,
+    /// The maximum number of bytes held in quarantine
+    pub bytes: usize,
+    /// Make quarantined slots inaccessible by moving them to a separate
+    /// protection key.  Keys apply to whole pages, so only slots which are
+    /// a multiple of the page size can be protected this way.
+    pub deny_access: bool,
+}
+
+struct Entry {
+    ptr: *mut u8,
+    size: usize,
+}
+
+/// Entries before `head` have been released, and are dropped from the
+/// front once they make up half the queue
+struct Queue {
+    entries: MetaVec<Entry>,
+    head: usize,
+    bytes: usize,
+}
+
+impl Queue {
+    fn held(&self) -> &[Entry] {
+        &self.entries[self.head..]
+    }
+}
+
+unsafe impl Send for Queue {}
+
+pub(crate) struct QuarantineList {
+    config: Quarantine,
+    queue: Mutex<Queue>,
+}
+
+impl QuarantineList {
+    pub(crate) fn new(config: Quarantine) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(Queue {
+                entries: MetaVec::new(),
+                head: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn denies_access(&self) -> bool {
+        self.config.deny_access
+    }
+
+    /// Add a freed slot of `size` bytes to the quarantine, calling `release`
+    /// for each slot which has now served its time.
+    pub(crate) fn push<F>(&self, ptr: *mut u8, size: usize, mut release: F)
+    where
+        F: FnMut(*mut u8, usize),
+    {
+        let mut released = MetaVec::new();
+        {
+            let mut queue = self.queue.lock();
+            queue.entries.push(Entry { ptr, size });
+            queue.bytes += size;
+            while queue.held().len() > self.config.slots || queue.bytes > self.config.bytes {
+                let Some(&Entry { ptr, size }) = queue.held().first() else {
+                    break;
+                };
+                queue.head += 1;
+                queue.bytes -= size;
+                released.push(Entry { ptr, size });
+            }
+            if queue.head * 2 >= queue.entries.len() {
+                let head = queue.head;
+                queue.entries.remove_front(head);
+                queue.head = 0;
+            }
+        }
+        // Release outside the lock, the bins have locks of their own
+        for entry in &released {
+            release(entry.ptr, entry.size);
+        }
+    }
+
+    /// Every slot currently in quarantine, sorted by address
+    pub(crate) fn slots_held(&self) -> MetaVec<*mut u8> {
+        let mut held = MetaVec::new();
+        held.extend(self.queue.lock().held().iter().map(|e| e.ptr));
+        held.sort_unstable();
+        held
+    }
+
+    pub(crate) fn slots(&self) -> usize {
+        self.queue.lock().held().len()
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.queue.lock().bytes
+    }
+
+    /// Forget everything in quarantine, the memory is about to be unmapped
+    pub(crate) fn clear(&self) {
+        let mut queue = self.queue.lock();
+        queue.entries.clear();
+        queue.head = 0;
+        queue.bytes = 0;
+    }
+}
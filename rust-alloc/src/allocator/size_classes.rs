@@ -0,0 +1,131 @@
+//! Size class tables
+//!
+//! Every allocation no larger than the biggest size class is served from
+//! the bin of the smallest class which fits it.  The gap between the
+//! request and its class is wasted, so finer grained classes waste less
+//! memory at the cost of more, emptier, bins.
+
+use super::MAX_ALIGN;
+
+/// The largest slot a size class may have
+pub(crate) const MAX_CLASS: usize = 0x10000;
+
+/// The smallest slot a size class may have, a free slot must be able to
+/// hold the pointer to the next
+const MIN_CLASS: usize = core::mem::size_of::<usize>();
+
+/// The size classes the bins of a label are built from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClasses {
+    /// One class per power of two, from 8 bytes to 64 KiB
+    #[default]
+    PowerOfTwo,
+    /// Four classes per power of two, from 8 bytes to 64 KiB, so no
+    /// allocation wastes more than a fifth of its slot once past 32 bytes
+    QuarterPowerOfTwo,
+    /// A custom table of slot sizes.  These must be in ascending order,
+    /// each a multiple of 8 bytes and no larger than 64 KiB.
+    Custom(&'static [usize]),
+}
+
+const POWER_OF_TWO: [usize; 14] = power_of_two_classes();
+const QUARTER_POWER_OF_TWO: [usize; 48] = quarter_power_of_two_classes();
+
+const fn power_of_two_classes() -> [usize; 14] {
+    let mut classes = [0; 14];
+    let mut i = 0;
+    while i < classes.len() {
+        classes[i] = MIN_CLASS << i;
+        i += 1;
+    }
+    classes
+}
+
+const fn quarter_power_of_two_classes() -> [usize; 48] {
+    let mut classes = [0; 48];
+    // Below 32 bytes quarter steps would not be multiples of 8
+    classes[0] = 8;
+    classes[1] = 16;
+    classes[2] = 24;
+    let mut i = 3;
+    let mut base = 32;
+    while base < MAX_CLASS {
+        let mut quarter = 0;
+        while quarter < 4 {
+            classes[i] = base + quarter * (base / 4);
+            i += 1;
+            quarter += 1;
+        }
+        base *= 2;
+    }
+    classes[i] = MAX_CLASS;
+    classes
+}
+
+impl SizeClasses {
+    pub(crate) fn table(self) -> &'static [usize] {
+        match self {
+            SizeClasses::PowerOfTwo => &POWER_OF_TWO,
+            SizeClasses::QuarterPowerOfTwo => &QUARTER_POWER_OF_TWO,
+            SizeClasses::Custom(table) => table,
+        }
+    }
+
+    /// Whether the table of classes is usable
+    pub(crate) fn is_valid(self) -> bool {
+        let table = self.table();
+        !table.is_empty()
+            && table
+                .iter()
+                .all(|&c| (MIN_CLASS..=MAX_CLASS).contains(&c) && c % MIN_CLASS == 0)
+            && table.windows(2).all(|pair| pair[0] < pair[1])
+    }
+}
+
+/// The alignment every slot of `size` bytes is guaranteed, since slots are
+/// laid end to end from the start of a page aligned chunk
+pub(crate) const fn slot_align(size: usize) -> usize {
+    let align = 1 << size.trailing_zeros();
+    if align > MAX_ALIGN {
+        MAX_ALIGN
+    } else {
+        align
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tables_are_valid() {
+        assert!(SizeClasses::PowerOfTwo.is_valid());
+        assert!(SizeClasses::QuarterPowerOfTwo.is_valid());
+        assert_eq!(POWER_OF_TWO[13], MAX_CLASS);
+        assert!(!SizeClasses::Custom(&[]).is_valid());
+        assert!(!SizeClasses::Custom(&[16, 8]).is_valid());
+        assert!(!SizeClasses::Custom(&[8, 20]).is_valid());
+        assert!(!SizeClasses::Custom(&[8, MAX_CLASS * 2]).is_valid());
+    }
+
+    /// The fraction of slot bytes wasted when every size up to the largest
+    /// class is allocated once
+    fn internal_fragmentation(classes: SizeClasses) -> f64 {
+        let table = classes.table();
+        let (mut wasted, mut total) = (0, 0);
+        for size in 1..=MAX_CLASS {
+            let class = table[table.partition_point(|&c| c < size)];
+            wasted += class - size;
+            total += class;
+        }
+        wasted as f64 / total as f64
+    }
+
+    #[test]
+    fn quarter_classes_fragment_less() {
+        let coarse = internal_fragmentation(SizeClasses::PowerOfTwo);
+        let fine = internal_fragmentation(SizeClasses::QuarterPowerOfTwo);
+        assert!(coarse > 0.2, "power of two wastes {coarse}");
+        assert!(fine < 0.1, "quarter power of two wastes {fine}");
+    }
+}
@@ -0,0 +1,175 @@
+//! Reporting and catching faults on protection labels
+//!
+//! The report is written from within the signal handler, so everything it
+//! does must be async-signal safe: no allocation, and no waiting on locks.
+
+use core::fmt::{self, Write};
+use std::panic;
+
+use libc::c_int;
+use segvpanic::{Access, SegmentationViolation, SignalWriter};
+
+use crate::pkey::{pkey_get, pkey_set};
+use crate::registry::{self, LabelId, SignalSafe};
+use crate::ProtectionLevel;
+
+/// A fault caught by [`ProtectionLabel::try_access`], on memory a label
+/// denied access to
+///
+/// [`ProtectionLabel::try_access`]: crate::ProtectionLabel::try_access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessViolation {
+    label: LabelId,
+    fault: SegmentationViolation,
+}
+
+impl AccessViolation {
+    /// The label whose protection key denied the access
+    pub fn label(&self) -> LabelId {
+        self.label
+    }
+
+    /// The address whose access faulted
+    pub fn address(&self) -> *const u8 {
+        self.fault.address()
+    }
+
+    /// Whether the faulting instruction was reading or writing
+    pub fn access(&self) -> Access {
+        self.fault.access()
+    }
+
+    /// The fault as raised by `segvpanic`, with the rights of the thread
+    /// when it faulted
+    pub fn fault(&self) -> &SegmentationViolation {
+        &self.fault
+    }
+}
+
+impl fmt::Display for AccessViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access() {
+            Access::Read => "reading",
+            Access::Write => "writing",
+            _ => "accessing",
+        };
+        write!(f, "{} denied {access} {:p}", self.label, self.address())
+    }
+}
+
+impl std::error::Error for AccessViolation {}
+
+/// The number of protection keys whose rights are saved around
+/// [`try_access`]
+const KEYS: c_int = 16;
+
+/// Run `func`, turning a fault on any label into an [`AccessViolation`].
+/// Other panics carry on unwinding, as do faults on keys no label owns.
+///
+/// A fault returns here without unwinding, see [`segvpanic::recover`], so
+/// the rights to every key are restored afterwards.
+///
+/// # Safety
+///
+/// As for [`segvpanic::recover`]
+pub(crate) unsafe fn try_access<O>(func: impl FnOnce() -> O) -> Result<O, AccessViolation> {
+    let mut saved = [-1; KEYS as usize];
+    for (pkey, rights) in (0..KEYS).zip(&mut saved) {
+        *rights = pkey_get(pkey);
+    }
+    let result = segvpanic::recover(func);
+    for (pkey, &rights) in (0..KEYS).zip(&saved) {
+        if rights >= 0 {
+            pkey_set(pkey, rights);
+        }
+    }
+    result.map_err(|fault| match registry::key_owner(fault.key()) {
+        Some(label) => AccessViolation { label, fault },
+        None => panic::panic_any(fault),
+    })
+}
+
+/// Report every fault on a label to stderr, then turn it into a panic
+///
+/// The report names the label owning the faulting address, the access
+/// attempted, the faulting thread's level for every label, and a backtrace.
+/// Faults which can't be turned into panics are reported before the
+/// process is killed.
+pub fn report_faults() {
+    segvpanic::set_fault_hook(Some(report));
+    segvpanic::install();
+}
+
+fn report(fault: &SegmentationViolation) {
+    let _ = write_report(&mut SignalWriter::stderr(), fault);
+    segvpanic::write_backtrace(libc::STDERR_FILENO);
+}
+
+fn write_report(out: &mut impl Write, fault: &SegmentationViolation) -> fmt::Result {
+    let access = match fault.access() {
+        Access::Read => "reading",
+        Access::Write => "writing",
+        _ => "accessing",
+    };
+    write!(
+        out,
+        "rsbmalloc: protection fault {access} {:p}",
+        fault.address()
+    )?;
+    match registry::try_lookup(fault.address()) {
+        Some(owner) => write!(out, " in {}", SignalSafe(owner))?,
+        None => write!(out, " outside any label")?,
+    }
+    writeln!(out, ", denied by pkey {}", fault.key())?;
+    writeln!(out, "rsbmalloc: levels at the fault:")?;
+    for (key, owner) in registry::key_owners() {
+        write!(out, "  pkey {key}, {}: ", SignalSafe(owner))?;
+        match fault.rights_to(key) {
+            Some(rights) => writeln!(out, "{:?}", ProtectionLevel::from_flags(rights))?,
+            None => writeln!(out, "unknown")?,
+        }
+    }
+    writeln!(out, "rsbmalloc: backtrace:")
+}
+
+#[cfg(all(test, feature = "nightly"))]
+mod test {
+    use std::panic::AssertUnwindSafe;
+
+    use super::*;
+    use crate::{ProtectionError, ProtectionLabel};
+
+    #[test]
+    fn report_names_label_and_levels() -> Result<(), ProtectionError> {
+        segvpanic::install();
+        let label = ProtectionLabel::builder().name("secrets").create()?;
+        let other = ProtectionLabel::builder()
+            .name("scratch")
+            .level(ProtectionLevel::ReadOnly)
+            .create()?;
+        let secret = label.with_level(ProtectionLevel::ReadWrite, |l| Box::new_in(7u64, l));
+        let addr = &*secret as *const u64 as *mut u64;
+        let fault = segvpanic::catch_fault(AssertUnwindSafe(|| unsafe { addr.write_volatile(8) }))
+            .unwrap_err();
+        let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+
+        let mut report = String::new();
+        write_report(&mut report, fault).unwrap();
+        let first = report.lines().next().unwrap();
+        assert!(first.starts_with(&format!(
+            "rsbmalloc: protection fault writing {addr:p} in secrets (label #"
+        )));
+        assert!(first.ends_with(&format!("denied by pkey {}", label.key())));
+        assert!(report.contains(&format!("  pkey {}, secrets (label #", label.key())));
+        let level = |key| {
+            let line = report
+                .lines()
+                .find(|l| l.starts_with(&format!("  pkey {key},")))
+                .unwrap();
+            line.rsplit(": ").next().unwrap().to_owned()
+        };
+        assert_eq!(level(label.key()), "DenyAll");
+        assert_eq!(level(other.key()), "ReadOnly");
+        Ok(())
+    }
+}
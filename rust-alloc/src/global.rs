@@ -0,0 +1,158 @@
+//! A protection label serving as the process wide allocator
+//!
+//! ```no_run
+//! use rsbmalloc::{LabelledHeap, ProtectionLevel};
+//!
+//! #[global_allocator]
+//! static HEAP: LabelledHeap = LabelledHeap::new();
+//!
+//! fn call_plugin(plugin: fn()) {
+//!     // The plugin can't touch anything on the heap
+//!     HEAP.with_level(ProtectionLevel::DenyAll, plugin);
+//! }
+//! ```
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use libc::c_int;
+use spin::Once;
+
+use crate::allocator::{fatal, AllocError, Options, RSBMalloc};
+use crate::pkey::{pkey_alloc, pkey_get, pkey_set, register_faults, with_rights};
+use crate::signal::set_default_rights;
+use crate::{LabelId, LabelStats, ProtectionLevel};
+
+struct Heap {
+    label: c_int,
+    alloc: RSBMalloc,
+}
+
+/// A [`GlobalAlloc`] serving every allocation from a single protection label
+///
+/// The label is created on the first allocation, with read-write access for
+/// the allocating thread.  Access rights are per thread, and threads spawned
+/// later inherit the rights of the thread spawning them, so the heap should
+/// be used before any threads are spawned.
+///
+/// The allocator raises the calling thread's access while allocating and
+/// freeing, so dropping access around untrusted code doesn't stop it from
+/// allocating, but what it allocates is as inaccessible to it as the rest of
+/// the heap.
+pub struct LabelledHeap {
+    heap: Once<Heap>,
+}
+
+impl LabelledHeap {
+    pub const fn new() -> Self {
+        Self { heap: Once::new() }
+    }
+
+    fn heap(&self) -> &Heap {
+        self.heap.call_once(|| unsafe {
+            // Nothing here may allocate from the global allocator, that's us
+            let label = pkey_alloc(0, 0);
+            if label == -1 {
+                fatal(format_args!(
+                    "no protection key available for the global heap"
+                ));
+            }
+            let alloc = RSBMalloc::new(label, None, Options::default());
+            register_faults(label, alloc.id());
+            set_default_rights(label, Some(0));
+            Heap { label, alloc }
+        })
+    }
+
+    /// Unique for the life of the process, see [`crate::ProtectionLabel::owner_of`]
+    pub fn id(&self) -> LabelId {
+        self.heap().alloc.id()
+    }
+
+    /// The protection key backing the heap
+    pub fn key(&self) -> c_int {
+        self.heap().label
+    }
+
+    /// The calling thread's current access to the heap
+    pub fn level(&self) -> ProtectionLevel {
+        ProtectionLevel::from_flags(unsafe { pkey_get(self.heap().label) })
+    }
+
+    /// # Safety
+    ///
+    /// Almost everything in Rust touches the heap, the calling thread will
+    /// fault on the first access if it is restricted.
+    pub unsafe fn set_level(&self, level: ProtectionLevel) {
+        pkey_set(self.heap().label, level.to_flags());
+    }
+
+    /// Run `func` with the calling thread's access to the heap set to
+    /// `level`, restoring it afterwards even if `func` panics
+    pub fn with_level<F, O>(&self, level: ProtectionLevel, func: F) -> O
+    where
+        F: FnOnce() -> O,
+    {
+        self.heap().with_level(level, func)
+    }
+
+    /// Retrieve a snapshot of the allocation statistics for the heap
+    pub fn stats(&self) -> LabelStats {
+        self.heap().alloc.stats()
+    }
+}
+
+impl Default for LabelledHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    fn with_level<F, O>(&self, level: ProtectionLevel, func: F) -> O
+    where
+        F: FnOnce() -> O,
+    {
+        with_rights(self.label, level.to_flags(), func)
+    }
+}
+
+fn into_raw(ptr: Result<NonNull<[u8]>, AllocError>) -> *mut u8 {
+    ptr.map_or(ptr::null_mut(), |ptr| ptr.cast().as_ptr())
+}
+
+unsafe impl GlobalAlloc for LabelledHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = self.heap();
+        heap.with_level(ProtectionLevel::ReadWrite, || {
+            into_raw(heap.alloc.allocate(layout))
+        })
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let heap = self.heap();
+        heap.with_level(ProtectionLevel::ReadWrite, || {
+            into_raw(heap.alloc.allocate_zeroed(layout))
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let heap = self.heap();
+        heap.with_level(ProtectionLevel::ReadWrite, || {
+            heap.alloc.deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let heap = self.heap();
+        let ptr = NonNull::new_unchecked(ptr);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        heap.with_level(ProtectionLevel::ReadWrite, || {
+            into_raw(if new_size >= layout.size() {
+                heap.alloc.grow(ptr, layout, new_layout)
+            } else {
+                heap.alloc.shrink(ptr, layout, new_layout)
+            })
+        })
+    }
+}
@@ -1,16 +1,50 @@
-#![feature(allocator_api)]
-#![feature(slice_ptr_get)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
-use std::{alloc::Allocator, sync::Arc};
+extern crate alloc;
 
-use allocator::RSBMalloc;
+use alloc::{string::String, sync::Arc, vec::Vec};
+#[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+use core::alloc::Layout;
+use core::{fmt, ptr::NonNull};
+
+#[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+use allocator::AllocError;
+pub use allocator::{HugePages, Quarantine, SizeClasses};
+use allocator::{Options, RSBMalloc};
 use libc::c_int;
-use pkey::{pkey_alloc, pkey_free, pkey_get, pkey_set, PKEY_DISABLE_ACCESS, PKEY_DISABLE_WRITE};
+use pkey::{
+    pkey_alloc, pkey_free, pkey_get, pkey_set, register_faults, unregister_faults, with_rights,
+    PKEY_DISABLE_ACCESS, PKEY_DISABLE_WRITE,
+};
 use static_assertions::assert_impl_all;
-use thiserror::Error;
 
 mod allocator;
+#[cfg(feature = "segvpanic")]
+mod fault;
+mod global;
 pub(crate) mod pkey;
+mod registry;
+mod signal;
+#[cfg(feature = "std")]
+mod spawn;
+mod stats;
+#[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+mod thread_cache;
+
+/// Turns faults, such as touching a label without access, into panics
+#[cfg(feature = "segvpanic")]
+pub use fault::report_faults;
+#[cfg(feature = "segvpanic")]
+pub use fault::AccessViolation;
+pub use global::LabelledHeap;
+pub use registry::LabelId;
+#[cfg(feature = "segvpanic")]
+pub use segvpanic;
+pub use signal::with_signal_pkru;
+#[cfg(feature = "std")]
+pub use spawn::{protected_spawn, BuilderExt};
+pub use stats::{BinStats, LabelStats};
 
 #[derive(Clone)]
 pub struct ProtectionLabel {
@@ -19,17 +53,48 @@ pub struct ProtectionLabel {
 
 struct ProtectionLabelInner {
     label: c_int,
+    name: Option<String>,
     alloc: RSBMalloc,
 }
 
 assert_impl_all!(ProtectionLabelInner: Send, Sync);
 
-#[derive(Debug, Error)]
+#[derive(Debug)]
 pub enum ProtectionError {
-    #[error("The kernel has run out of protection labels to give to us")]
     OutOfLabels,
+    TrackingDisabled,
+    InvalidSizeClasses,
+    InvalidChunkSize,
+    ReservationFailed,
+    ArenaDisabled,
 }
 
+impl fmt::Display for ProtectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProtectionError::OutOfLabels => {
+                "The kernel has run out of protection labels to give to us"
+            }
+            ProtectionError::TrackingDisabled => {
+                "Allocation tracking was not enabled for this protection label"
+            }
+            ProtectionError::InvalidSizeClasses => {
+                "Size classes must ascend in multiples of 8 bytes, up to 64 KiB"
+            }
+            ProtectionError::InvalidChunkSize => {
+                "The chunk size is too large to round up to whole pages"
+            }
+            ProtectionError::ReservationFailed => {
+                "The address space for the arena could not be reserved"
+            }
+            ProtectionError::ArenaDisabled => "No arena was reserved for this protection label",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtectionError {}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ProtectionLevel {
     DenyAll,
@@ -45,23 +110,200 @@ impl ProtectionLevel {
             ProtectionLevel::ReadWrite => 0,
         }
     }
+
+    fn from_flags(flags: c_int) -> Self {
+        if flags & PKEY_DISABLE_ACCESS != 0 {
+            ProtectionLevel::DenyAll
+        } else if flags & PKEY_DISABLE_WRITE != 0 {
+            ProtectionLevel::ReadOnly
+        } else {
+            ProtectionLevel::ReadWrite
+        }
+    }
 }
 
-impl ProtectionLabel {
-    pub fn create(level: ProtectionLevel) -> Result<Self, ProtectionError> {
+/// Builder for protection labels with non-default options
+///
+/// Labels built without setting a level start out as [`ProtectionLevel::DenyAll`].
+#[derive(Debug, Clone)]
+pub struct ProtectionLabelBuilder {
+    level: ProtectionLevel,
+    default_level: ProtectionLevel,
+    name: Option<String>,
+    options: Options,
+}
+
+impl ProtectionLabelBuilder {
+    /// A name to identify the label by in diagnostics, such as its
+    /// [`Debug`] and [`Display`] output and fault reports
+    ///
+    /// [`Display`]: fmt::Display
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// The level the label is set to for the creating thread
+    pub fn level(mut self, level: ProtectionLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The level the label gets inside [`with_signal_pkru`], and in threads
+    /// started by `protected_spawn`, [`ProtectionLevel::DenyAll`] by default
+    ///
+    /// This is kept by the crate, the kernel never sees it: a handler
+    /// installed with plain `sigaction` still runs with the kernel's
+    /// `init_pkru`, whatever the label's default.
+    pub fn default_level(mut self, level: ProtectionLevel) -> Self {
+        self.default_level = level;
+        self
+    }
+
+    /// Record large allocations so that [`ProtectionLabel::walk`] can
+    /// enumerate every live allocation in the label
+    ///
+    /// Tracking labels don't use thread caches, see [`Self::thread_cache`].
+    pub fn track_allocations(mut self, track: bool) -> Self {
+        self.options.track_allocations = track;
+        self
+    }
+
+    /// Validate every free, aborting the process with a diagnostic if a
+    /// pointer is freed twice, or was never allocated from this label
+    pub fn hardened(mut self, hardened: bool) -> Self {
+        self.options.hardened = hardened;
+        self
+    }
+
+    /// Serve small allocations from per-thread caches, avoiding contention
+    /// on the label's bins when many threads allocate from it.
+    ///
+    /// Thread caches are not used in hardened mode, with a quarantine, or
+    /// when tracking allocations, as [`ProtectionLabel::walk`] would report
+    /// slots cached by other threads as live.  Nor are they used without the
+    /// `std` feature.
+    pub fn thread_cache(mut self, thread_cache: bool) -> Self {
+        self.options.thread_cache = thread_cache;
+        self
+    }
+
+    /// Hold freed slots back from reuse for a while, see [`Quarantine`]
+    ///
+    /// If the quarantine denies access to its slots, a second protection
+    /// key is allocated for the label.
+    pub fn quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.options.quarantine = Some(quarantine);
+        self
+    }
+
+    /// The slot sizes of the label's bins, see [`SizeClasses`]
+    ///
+    /// Allocations larger than the biggest class are mapped directly.
+    pub fn size_classes(mut self, size_classes: SizeClasses) -> Self {
+        self.options.size_classes = size_classes;
+        self
+    }
+
+    /// The size of the chunks the label's bins are carved from, 64 KiB by
+    /// default
+    ///
+    /// Each bin maps at least one chunk once used, so a small label holding
+    /// a few keys can use a page sized chunk, while a label with many
+    /// allocations can use larger chunks to map less often.  Chunks are
+    /// rounded up to whole pages, and to at least one slot of their bin.
+    /// Creating the label fails with [`ProtectionError::InvalidChunkSize`]
+    /// if the rounded size can't be mapped.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.options.chunk_size = Some(bytes);
+        self
+    }
+
+    /// Back mappings of 2 MiB or more with huge pages, see [`HugePages`],
+    /// reducing TLB pressure for labels holding a lot of memory
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> Self {
+        self.options.huge_pages = huge_pages;
+        self
+    }
+
+    /// Reserve `bytes` of address space for the label up front, and commit
+    /// all of its memory from that one range
+    ///
+    /// This makes [`ProtectionLabel::contains`] a simple range check, but
+    /// allocations fail once the arena is exhausted.
+    pub fn reserve(mut self, bytes: usize) -> Self {
+        self.options.reserve = Some(bytes);
+        self
+    }
+
+    pub fn create(self) -> Result<ProtectionLabel, ProtectionError> {
+        if !self.options.size_classes.is_valid() {
+            return Err(ProtectionError::InvalidSizeClasses);
+        }
+        if !self.options.chunk_size_is_valid() {
+            return Err(ProtectionError::InvalidChunkSize);
+        }
         unsafe {
             let label = pkey_alloc(0, 0);
             if label == -1 {
                 return Err(ProtectionError::OutOfLabels);
             }
-            let alloc = RSBMalloc::new(label);
-            let ret = Self {
-                inner: Arc::new(ProtectionLabelInner { label, alloc }),
+            let quarantine_label = match self.options.quarantine {
+                Some(q) if q.deny_access => {
+                    let quarantine_label = pkey_alloc(0, PKEY_DISABLE_ACCESS);
+                    if quarantine_label == -1 {
+                        pkey_free(label);
+                        return Err(ProtectionError::OutOfLabels);
+                    }
+                    Some(quarantine_label)
+                }
+                _ => None,
+            };
+            let mut alloc = RSBMalloc::new(label, quarantine_label, self.options);
+            if let Some(size) = self.options.reserve {
+                if !alloc.reserve(size) {
+                    pkey_free(label);
+                    if let Some(quarantine_label) = quarantine_label {
+                        pkey_free(quarantine_label);
+                    }
+                    return Err(ProtectionError::ReservationFailed);
+                }
+            }
+            if let Some(name) = &self.name {
+                registry::set_name(alloc.id(), name);
+            }
+            register_faults(label, alloc.id());
+            signal::set_default_rights(label, Some(self.default_level.to_flags()));
+            if let Some(quarantine_label) = quarantine_label {
+                register_faults(quarantine_label, alloc.id());
+                signal::set_default_rights(quarantine_label, Some(PKEY_DISABLE_ACCESS));
+            }
+            let ret = ProtectionLabel {
+                inner: Arc::new(ProtectionLabelInner {
+                    label,
+                    name: self.name,
+                    alloc,
+                }),
             };
-            ret.set_level(level);
+            ret.set_level(self.level);
             Ok(ret)
         }
     }
+}
+
+impl ProtectionLabel {
+    pub fn builder() -> ProtectionLabelBuilder {
+        ProtectionLabelBuilder {
+            level: ProtectionLevel::DenyAll,
+            default_level: ProtectionLevel::DenyAll,
+            name: None,
+            options: Options::default(),
+        }
+    }
+
+    pub fn create(level: ProtectionLevel) -> Result<Self, ProtectionError> {
+        Self::builder().level(level).create()
+    }
 
     /// # Safety
     ///
@@ -72,83 +314,335 @@ impl ProtectionLabel {
         pkey_set(self.inner.label, level.to_flags());
     }
 
+    /// Run `func` with the calling thread's access to the label set to
+    /// `level`, restoring it afterwards even if `func` panics
     pub fn with_level<F, O>(&self, level: ProtectionLevel, func: F) -> O
     where
         F: FnOnce(ProtectionLabel) -> O,
     {
-        let cur = unsafe {
-            let cur = pkey_get(self.inner.label);
-            pkey_set(self.inner.label, level.to_flags());
-            cur
+        self.inner.with_level(level, || func(self.clone()))
+    }
+
+    /// As [`Self::with_level`], but a fault on any label's memory while
+    /// running `func` is caught and returned as an error, rather than
+    /// killing the process.  Other panics carry on unwinding.
+    ///
+    /// The calling thread's level for every label is restored afterwards,
+    /// including any changed by `func` before it faulted.  A fault returns
+    /// straight here without unwinding, so nothing owned by the frames
+    /// between is dropped, see [`segvpanic::recover`].
+    ///
+    /// # Safety
+    ///
+    /// No frame within `func` which could be abandoned by a fault may rely
+    /// on its cleanups running for soundness, as [`std::thread::scope`] and
+    /// pinned values do.
+    #[cfg(feature = "segvpanic")]
+    pub unsafe fn try_access<F, O>(
+        &self,
+        level: ProtectionLevel,
+        func: F,
+    ) -> Result<O, AccessViolation>
+    where
+        F: FnOnce(ProtectionLabel) -> O,
+    {
+        fault::try_access(|| self.with_level(level, func))
+    }
+
+    /// Unique for the life of the process, unlike the protection key
+    pub fn id(&self) -> LabelId {
+        self.inner.alloc.id()
+    }
+
+    /// The name the label was built with, if any
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name.as_deref()
+    }
+
+    /// The protection key backing this label
+    ///
+    /// Keys are reused by the kernel once a label is dropped, see
+    /// [`Self::id`] for an identifier which isn't.
+    pub fn key(&self) -> c_int {
+        self.inner.label
+    }
+
+    /// The calling thread's current access to this label
+    pub fn level(&self) -> ProtectionLevel {
+        ProtectionLevel::from_flags(unsafe { pkey_get(self.inner.label) })
+    }
+
+    /// The label whose memory `ptr` points into, if any
+    ///
+    /// Every label's mappings are recorded in a process wide registry, so
+    /// this finds the owner of a pointer into any part of a bin's chunk or
+    /// a large allocation, or into a label's arena.
+    pub fn owner_of(ptr: *const u8) -> Option<LabelId> {
+        registry::lookup(ptr)
+    }
+
+    /// Retrieve a snapshot of the allocation statistics for this label
+    pub fn stats(&self) -> LabelStats {
+        self.inner.alloc.stats()
+    }
+
+    /// The address range of the label's arena, if one was reserved
+    pub fn arena(&self) -> Option<NonNull<[u8]>> {
+        self.inner.alloc.arena()
+    }
+
+    /// Whether `ptr` points into memory belonging to this label
+    ///
+    /// This is only possible for labels with an arena, see
+    /// [`ProtectionLabelBuilder::reserve`].
+    pub fn contains(&self, ptr: *const u8) -> Result<bool, ProtectionError> {
+        self.inner
+            .alloc
+            .arena_contains(ptr)
+            .ok_or(ProtectionError::ArenaDisabled)
+    }
+
+    /// Call `func` with the address and size of every live allocation in
+    /// this label.
+    ///
+    /// Allocations in bins are reported with the size of their slot rather
+    /// than the size originally requested.  The label is read while walking
+    /// so this briefly raises the calling thread's access to at least
+    /// [`ProtectionLevel::ReadOnly`], but `func` is called with the thread's
+    /// original access level.
+    pub fn walk<F>(&self, mut func: F) -> Result<(), ProtectionError>
+    where
+        F: FnMut(NonNull<u8>, usize),
+    {
+        if !self.inner.alloc.tracks_allocations() {
+            return Err(ProtectionError::TrackingDisabled);
+        }
+        let mut live = Vec::new();
+        let cur = unsafe { pkey_get(self.inner.label) };
+        let level = if cur == ProtectionLevel::ReadWrite.to_flags() {
+            ProtectionLevel::ReadWrite
+        } else {
+            ProtectionLevel::ReadOnly
         };
-        let ret = func(self.clone());
-        unsafe {
-            pkey_set(self.inner.label, cur);
+        self.with_level(level, |_| unsafe {
+            self.inner.alloc.walk(|ptr, size| live.push((ptr, size)))
+        });
+        for (ptr, size) in live {
+            func(ptr, size);
         }
-        ret
+        Ok(())
     }
 }
 
-impl Drop for ProtectionLabelInner {
-    fn drop(&mut self) {
-        unsafe {
-            self.alloc.free_all();
-            pkey_free(self.label);
+/// Shows the label's name, or its id if it has none, and its key
+impl fmt::Display for ProtectionLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name} (pkey {})", self.key()),
+            None => write!(f, "{} (pkey {})", self.id(), self.key()),
         }
     }
 }
 
-unsafe impl Allocator for ProtectionLabel {
-    fn allocate(
-        &self,
-        layout: std::alloc::Layout,
-    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        self.inner.alloc.allocate(layout)
+impl fmt::Debug for ProtectionLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtectionLabel")
+            .field("id", &self.id())
+            .field("name", &self.name())
+            .field("key", &self.key())
+            .field("level", &self.level())
+            .field("stats", &self.stats())
+            .finish()
     }
+}
 
-    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: std::alloc::Layout) {
-        self.with_level(ProtectionLevel::ReadWrite, |_| {
-            self.inner.alloc.deallocate(ptr, layout)
+impl ProtectionLabelInner {
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+    fn allocate(self: &Arc<Self>, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        #[cfg(feature = "std")]
+        if let Some(ptr) = thread_cache::allocate(self, layout) {
+            return Ok(ptr);
+        }
+        self.alloc.allocate(layout)
+    }
+
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+    unsafe fn deallocate(self: &Arc<Self>, ptr: NonNull<u8>, layout: Layout) {
+        #[cfg(feature = "std")]
+        if thread_cache::deallocate(self, ptr, layout) {
+            return;
+        }
+        self.with_level(ProtectionLevel::ReadWrite, || {
+            self.alloc.deallocate(ptr, layout)
         })
     }
 
-    fn allocate_zeroed(
-        &self,
-        layout: std::alloc::Layout,
-    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        self.inner.alloc.allocate_zeroed(layout)
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+    fn allocate_zeroed(self: &Arc<Self>, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        unsafe {
+            ptr.cast::<u8>().as_ptr().write_bytes(0, ptr.len());
+        }
+        Ok(ptr)
     }
 
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
     unsafe fn grow(
-        &self,
-        ptr: std::ptr::NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        self.inner.alloc.grow(ptr, old_layout, new_layout)
+        self: &Arc<Self>,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        #[cfg(feature = "std")]
+        if let Some(new_ptr) = self.recache(ptr, old_layout, new_layout, zeroed) {
+            return new_ptr;
+        }
+        if zeroed {
+            self.alloc.grow_zeroed(ptr, old_layout, new_layout)
+        } else {
+            self.alloc.grow(ptr, old_layout, new_layout)
+        }
     }
 
-    unsafe fn grow_zeroed(
-        &self,
-        ptr: std::ptr::NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        self.inner.alloc.grow_zeroed(ptr, old_layout, new_layout)
+    #[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+    unsafe fn shrink(
+        self: &Arc<Self>,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        #[cfg(feature = "std")]
+        if let Some(new_ptr) = self.recache(ptr, old_layout, new_layout, false) {
+            return new_ptr;
+        }
+        self.alloc.shrink(ptr, old_layout, new_layout)
     }
 
-    unsafe fn shrink(
-        &self,
-        ptr: std::ptr::NonNull<u8>,
-        old_layout: std::alloc::Layout,
-        new_layout: std::alloc::Layout,
-    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        self.inner.alloc.shrink(ptr, old_layout, new_layout)
+    /// Move an allocation which could have come from this thread's cache to
+    /// one of `new_layout`, through the cache both ways, or `None` if the
+    /// allocator should move it instead
+    #[cfg(all(feature = "std", any(feature = "nightly", feature = "allocator-api2")))]
+    unsafe fn recache(
+        self: &Arc<Self>,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Option<Result<NonNull<[u8]>, AllocError>> {
+        self.alloc.cache_index(old_layout)?;
+        let new_ptr = if zeroed {
+            self.allocate_zeroed(new_layout)
+        } else {
+            self.allocate(new_layout)
+        };
+        Some(new_ptr.map(|new_ptr| {
+            core::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size().min(new_layout.size()),
+            );
+            self.deallocate(ptr, old_layout);
+            new_ptr
+        }))
+    }
+
+    fn with_level<F, O>(&self, level: ProtectionLevel, func: F) -> O
+    where
+        F: FnOnce() -> O,
+    {
+        with_rights(self.label, level.to_flags(), func)
     }
 }
 
-#[cfg(test)]
+impl Drop for ProtectionLabelInner {
+    fn drop(&mut self) {
+        unsafe {
+            self.alloc.free_all();
+            unregister_faults(self.label);
+            signal::set_default_rights(self.label, None);
+            pkey_free(self.label);
+            if let Some(quarantine_label) = self.alloc.quarantine_pkey() {
+                unregister_faults(quarantine_label);
+                signal::set_default_rights(quarantine_label, None);
+                pkey_free(quarantine_label);
+            }
+        }
+    }
+}
+
+/// The nightly and `allocator-api2` traits differ only in where they live
+#[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+macro_rules! impl_allocator {
+    ($($api:ident)::+) => {
+        unsafe impl $($api)::+::Allocator for ProtectionLabel {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, $($api)::+::AllocError> {
+                self.inner
+                    .allocate(layout)
+                    .map_err(|_| $($api)::+::AllocError)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.inner.deallocate(ptr, layout)
+            }
+
+            fn allocate_zeroed(
+                &self,
+                layout: Layout,
+            ) -> Result<NonNull<[u8]>, $($api)::+::AllocError> {
+                self.inner
+                    .allocate_zeroed(layout)
+                    .map_err(|_| $($api)::+::AllocError)
+            }
+
+            unsafe fn grow(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, $($api)::+::AllocError> {
+                self.inner
+                    .grow(ptr, old_layout, new_layout, false)
+                    .map_err(|_| $($api)::+::AllocError)
+            }
+
+            unsafe fn grow_zeroed(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, $($api)::+::AllocError> {
+                self.inner
+                    .grow(ptr, old_layout, new_layout, true)
+                    .map_err(|_| $($api)::+::AllocError)
+            }
+
+            unsafe fn shrink(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, $($api)::+::AllocError> {
+                self.inner
+                    .shrink(ptr, old_layout, new_layout)
+                    .map_err(|_| $($api)::+::AllocError)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "nightly")]
+impl_allocator!(core::alloc);
+
+// With `nightly`, allocator-api2 re-exports the core trait implemented above
+#[cfg(all(feature = "allocator-api2", not(feature = "nightly")))]
+impl_allocator!(allocator_api2::alloc);
+
+// The tests lean on `Box::new_in` and friends
+#[cfg(all(test, feature = "nightly"))]
 mod test {
+    use std::alloc::Allocator;
+
     use super::*;
 
     #[test]
@@ -186,4 +680,537 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn levels_restored_after_panic() -> Result<(), ProtectionError> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::create(DenyAll)?;
+        let heap = LabelledHeap::new();
+
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            label.with_level(ReadWrite, |_| {
+                heap.with_level(DenyAll, || panic!("dropped the secret"))
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(label.level(), DenyAll);
+        assert_eq!(heap.level(), ReadWrite);
+        Ok(())
+    }
+
+    #[test]
+    fn walk_live_allocations() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .track_allocations(true)
+            .create()?;
+
+        let keep: Vec<u64, _> = Vec::with_capacity_in(4, label.clone());
+        let gone: Vec<u64, _> = Vec::with_capacity_in(4, label.clone());
+        let large: Vec<u8, _> = Vec::with_capacity_in(0x20000, label.clone());
+        drop(gone);
+
+        let mut seen = Vec::new();
+        label.walk(|ptr, size| seen.push((ptr.as_ptr() as *const u8, size)))?;
+        seen.sort();
+        let mut expected = vec![(keep.as_ptr() as *const u8, 32), (large.as_ptr(), 0x20000)];
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        let untracked = ProtectionLabel::create(ReadWrite)?;
+        assert!(matches!(
+            untracked.walk(|_, _| ()),
+            Err(ProtectionError::TrackingDisabled)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn quarantine_delays_reuse() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .quarantine(Quarantine {
+                slots: 4,
+                bytes: usize::MAX,
+                deny_access: true,
+            })
+            .create()?;
+
+        let first = Box::new_in([0u8; 4096], label.clone());
+        let first_addr = &*first as *const [u8; 4096];
+        drop(first);
+        assert_eq!(label.stats().quarantined_slots, 1);
+
+        // While quarantined, the slot isn't handed out again
+        let mut held = Vec::new();
+        for _ in 0..4 {
+            let b = Box::new_in([0u8; 4096], label.clone());
+            assert_ne!(&*b as *const [u8; 4096], first_addr);
+            held.push(b);
+        }
+        // Freeing four more pushes the first out of quarantine
+        drop(held);
+        assert_eq!(label.stats().quarantined_slots, 4);
+        let again = Box::new_in([0u8; 4096], label.clone());
+        assert_eq!(&*again as *const [u8; 4096], first_addr);
+
+        Ok(())
+    }
+
+    // Threads only cache slots with `std`
+    #[test]
+    #[cfg(feature = "std")]
+    fn thread_cache() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .thread_cache(true)
+            .create()?;
+
+        let first = Box::new_in(1u64, label.clone());
+        let first_addr = &*first as *const u64;
+        drop(first);
+        // Freed to this thread's cache, so nothing is on the free list
+        let bin8 = label.stats().bins[0];
+        assert_eq!(bin8.free_slots, 0);
+        let again = Box::new_in(2u64, label.clone());
+        assert_eq!(&*again as *const u64, first_addr);
+
+        // Other threads get their own cache, flushed when they exit
+        let worker = label.clone();
+        std::thread::spawn(move || {
+            let v: Vec<_> = (0..100u64)
+                .map(|i| Box::new_in([i; 2], worker.clone()))
+                .collect();
+            drop(v);
+        })
+        .join()
+        .unwrap();
+        let bin16 = label.stats().bins[1];
+        assert_eq!(bin16.live_slots, 0);
+        assert!(bin16.free_slots >= 100);
+
+        // Growing and shrinking go through the cache too
+        let mut grown = Vec::with_capacity_in(1, label.clone());
+        grown.push(3u64);
+        let grown_from = grown.as_ptr();
+        grown.reserve_exact(3);
+        let reused = Box::new_in(4u64, label.clone());
+        assert_eq!(&*reused as *const u64, grown_from);
+        grown.shrink_to_fit();
+        assert_eq!(grown, [3]);
+        assert_eq!(label.stats().bins[2].free_slots, 0);
+
+        // Walking needs every free slot on a free list, so tracking labels
+        // don't cache
+        let tracked = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .thread_cache(true)
+            .track_allocations(true)
+            .create()?;
+        drop(Box::new_in(5u64, tracked.clone()));
+        assert_eq!(tracked.stats().bins[0].free_slots, 1);
+        tracked.walk(|ptr, _| panic!("freed {ptr:p} walked as live"))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_classes() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .size_classes(SizeClasses::QuarterPowerOfTwo)
+            .create()?;
+
+        let small = Box::new_in([0u64; 5], label.clone());
+        let stats = label.stats();
+        let bin40 = stats.bins.iter().find(|b| b.slot_size == 40).unwrap();
+        assert_eq!(bin40.live_slots, 1);
+        drop(small);
+
+        // Anything above the largest custom class is a large allocation
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .size_classes(SizeClasses::Custom(&[24, 96, 1000]))
+            .create()?;
+        let small = Box::new_in([0u8; 80], label.clone());
+        let large = Box::new_in([0u8; 1001], label.clone());
+        let stats = label.stats();
+        assert_eq!(stats.bins[1].live_slots, 1);
+        assert_eq!(stats.large_allocations, 1);
+        drop((small, large));
+
+        assert!(matches!(
+            ProtectionLabel::builder()
+                .size_classes(SizeClasses::Custom(&[16, 12]))
+                .create(),
+            Err(ProtectionError::InvalidSizeClasses)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .chunk_size(1)
+            .create()?;
+        let key = Box::new_in([0u8; 32], label.clone());
+        assert_eq!(label.stats().mapped_bytes, page);
+        drop(key);
+
+        // A chunk holds at least one slot, however small the chunk size
+        let big = Box::new_in([0u8; 0x10000], label.clone());
+        assert_eq!(label.stats().mapped_bytes, page + 0x10000);
+        drop(big);
+
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .chunk_size(0x100000)
+            .create()?;
+        let bigs: Vec<_> = (0..16)
+            .map(|_| Box::new_in([0u8; 0x10000], label.clone()))
+            .collect();
+        let stats = label.stats();
+        let bin64k = stats.bins.iter().find(|b| b.slot_size == 0x10000).unwrap();
+        assert_eq!(bin64k.chunks, 1);
+        assert_eq!(stats.mapped_bytes, 0x100000);
+        drop(bigs);
+
+        // Too big to round up to whole pages, or to map once rounded
+        for huge_pages in [HugePages::Never, HugePages::Transparent] {
+            for chunk_size in [usize::MAX, isize::MAX as usize] {
+                assert!(matches!(
+                    ProtectionLabel::builder()
+                        .chunk_size(chunk_size)
+                        .huge_pages(huge_pages)
+                        .create(),
+                    Err(ProtectionError::InvalidChunkSize)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Huge pages free in the hugetlbfs pool
+    fn hugetlb_pages_free() -> usize {
+        std::fs::read_to_string("/proc/meminfo")
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("HugePages_Free:"))
+            .map_or(0, |free| free.trim().parse().unwrap())
+    }
+
+    #[test]
+    fn huge_pages() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        // The advice is only refused by kernels without transparent huge pages
+        let transparent = std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists();
+        for huge_pages in [HugePages::Transparent, HugePages::HugeTlb] {
+            let free = hugetlb_pages_free();
+            if huge_pages == HugePages::HugeTlb && (1..3).contains(&free) {
+                // Too few for all three mappings, which ones get them is a race
+                continue;
+            }
+            let from_pool = huge_pages == HugePages::HugeTlb && free > 0;
+            let expected = |bytes| match (from_pool, transparent) {
+                (true, _) => (bytes, 0),
+                (false, true) => (0, bytes),
+                (false, false) => (0, 0),
+            };
+            let label = ProtectionLabel::builder()
+                .level(ReadWrite)
+                .huge_pages(huge_pages)
+                .create()?;
+
+            let mut large: Vec<u8, _> = Vec::with_capacity_in(0x300000, label.clone());
+            large.resize(0x300000, 7);
+            assert_eq!(large.as_ptr() as usize % 0x200000, 0);
+            let stats = label.stats();
+            assert_eq!(stats.mapped_bytes, 0x400000);
+            assert_eq!(
+                (stats.hugetlb_bytes, stats.transparent_huge_page_bytes),
+                expected(0x400000)
+            );
+
+            // Bins map whole huge pages too
+            let small = Box::new_in(0u64, label.clone());
+            assert_eq!(label.stats().mapped_bytes, 0x600000);
+
+            drop((large, small));
+            let stats = label.stats();
+            assert_eq!(stats.mapped_bytes, 0x200000);
+            assert_eq!(
+                (stats.hugetlb_bytes, stats.transparent_huge_page_bytes),
+                expected(0x200000)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn arena() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .reserve(0x100000)
+            .create()?;
+        let other = ProtectionLabel::create(ReadWrite)?;
+        assert_eq!(label.stats().reserved_bytes, 0x100000);
+
+        let small = Box::new_in(1u64, label.clone());
+        let mut large: Vec<u8, _> = Vec::with_capacity_in(0x20000, label.clone());
+        large.resize(0x20000, 1);
+        let foreign = Box::new_in(1u64, other.clone());
+        assert!(label.contains(&*small as *const u64 as *const u8)?);
+        assert!(label.contains(large.as_ptr())?);
+        assert!(!label.contains(&*foreign as *const u64 as *const u8)?);
+        assert!(matches!(
+            other.contains(large.as_ptr()),
+            Err(ProtectionError::ArenaDisabled)
+        ));
+
+        // Growing moves within the arena
+        large.resize(0x40000, 2);
+        assert!(label.contains(large.as_ptr())?);
+        assert_eq!(label.stats().mapped_bytes, 0x10000 + 0x40000);
+
+        // Once exhausted, allocations fail rather than escaping the arena
+        let layout = std::alloc::Layout::from_size_align(0x100000, 8).unwrap();
+        assert!(label.allocate(layout).is_err());
+
+        drop(large);
+        assert_eq!(label.stats().mapped_bytes, 0x10000);
+
+        // Too big to round up to whole pages, or to map at all
+        for size in [usize::MAX, isize::MAX as usize] {
+            assert!(matches!(
+                ProtectionLabel::builder().reserve(size).create(),
+                Err(ProtectionError::ReservationFailed)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn owner_of() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let a = ProtectionLabel::create(ReadWrite)?;
+        let b = ProtectionLabel::builder()
+            .level(ReadWrite)
+            .reserve(0x100000)
+            .create()?;
+        assert_ne!(a.id(), b.id());
+
+        let small = Box::new_in(1u64, a.clone());
+        let mut large: Vec<u8, _> = Vec::with_capacity_in(0x20000, a.clone());
+        large.resize(0x20000, 1);
+        let in_arena = Box::new_in(1u64, b.clone());
+        let heap = Box::new(1u64);
+        let owner_of = |ptr: *const u64| ProtectionLabel::owner_of(ptr as *const u8);
+        assert_eq!(owner_of(&*small), Some(a.id()));
+        assert_eq!(ProtectionLabel::owner_of(&large[0x1ffff]), Some(a.id()));
+        assert_eq!(owner_of(&*in_arena), Some(b.id()));
+        assert_eq!(owner_of(&*heap), None);
+
+        // Moving a large allocation moves its ownership
+        let old = large.as_ptr();
+        large.resize(0x400000, 2);
+        assert_eq!(ProtectionLabel::owner_of(&large[0x3fffff]), Some(a.id()));
+        if large.as_ptr() != old {
+            assert_eq!(ProtectionLabel::owner_of(old), None);
+        }
+
+        let small_addr = &*small as *const u64;
+        let id = a.id();
+        drop((small, large, a));
+        assert_ne!(owner_of(small_addr), Some(id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn names() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .name("tls-keys")
+            .level(ReadOnly)
+            .create()?;
+        assert_eq!(label.name(), Some("tls-keys"));
+        assert_eq!(label.level(), ReadOnly);
+        assert_eq!(
+            label.to_string(),
+            format!("tls-keys (pkey {})", label.key())
+        );
+        let debug = format!("{label:?}");
+        assert!(debug.contains("\"tls-keys\""), "{debug}");
+        assert!(debug.contains("level: ReadOnly"), "{debug}");
+        assert!(debug.contains("live_slots"), "{debug}");
+
+        // Ids found by address know their label's name too
+        let key = label.with_level(ReadWrite, |l| Box::new_in(7u64, l));
+        let owner = ProtectionLabel::owner_of(&*key as *const u64 as *const u8).unwrap();
+        assert_eq!(owner, label.id());
+        assert!(owner.to_string().starts_with("tls-keys (label #"));
+
+        let unnamed = ProtectionLabel::create(ReadWrite)?;
+        assert_eq!(unnamed.name(), None);
+        assert_eq!(
+            unnamed.to_string(),
+            format!("{} (pkey {})", unnamed.id(), unnamed.key())
+        );
+
+        label.with_level(ReadWrite, |_| drop(key));
+        let id = label.id();
+        drop(label);
+        assert_eq!(id.name(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn label_stats() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::create(ReadWrite)?;
+
+        let small: Vec<u64, _> = Vec::with_capacity_in(4, label.clone());
+        let large: Vec<u8, _> = Vec::with_capacity_in(0x20000, label.clone());
+
+        let stats = label.stats();
+        let bin32 = stats.bins.iter().find(|b| b.slot_size == 32).unwrap();
+        assert_eq!(bin32.live_slots, 1);
+        assert_eq!(bin32.chunks, 1);
+        assert_eq!(stats.large_allocations, 1);
+        assert_eq!(stats.large_bytes, 0x20000);
+        assert!(stats.mapped_bytes >= 0x20000 + 0x10000);
+
+        drop(small);
+        drop(large);
+
+        let stats = label.stats();
+        let bin32 = stats.bins.iter().find(|b| b.slot_size == 32).unwrap();
+        assert_eq!(bin32.live_slots, 0);
+        assert_eq!(bin32.free_slots, 1);
+        assert_eq!(stats.large_allocations, 0);
+        assert_eq!(stats.large_bytes, 0);
+        assert_eq!(stats.mapped_bytes, 0x10000);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "segvpanic")]
+    fn faults_become_panics() -> Result<(), ProtectionError> {
+        use segvpanic::SegmentationViolation;
+        use std::panic::AssertUnwindSafe;
+
+        segvpanic::install();
+        let label = ProtectionLabel::create(ProtectionLevel::DenyAll)?;
+        let secret = label.with_level(ProtectionLevel::ReadWrite, |l| Box::new_in(7u64, l));
+        let addr = &*secret as *const u64;
+        let fault = segvpanic::catch_fault(AssertUnwindSafe(|| unsafe { addr.read_volatile() }))
+            .unwrap_err();
+        let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+        assert_eq!(fault.address(), addr as *const u8);
+        assert_eq!(fault.key(), label.key());
+        assert_eq!(fault.access(), segvpanic::Access::Read);
+        label.with_level(ProtectionLevel::ReadOnly, |_| assert_eq!(*secret, 7));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "segvpanic")]
+    fn try_access_catches_faults() -> Result<(), ProtectionError> {
+        let secrets = ProtectionLabel::create(ProtectionLevel::DenyAll)?;
+        let plugin = ProtectionLabel::create(ProtectionLevel::DenyAll)?;
+        let secret = secrets.with_level(ProtectionLevel::ReadWrite, |l| Box::new_in(7u64, l));
+        let addr = &*secret as *const u64;
+
+        let sum = unsafe {
+            plugin.try_access(ProtectionLevel::ReadWrite, |plugin| {
+                let mut v = Vec::new_in(plugin);
+                v.extend(1..=4u64);
+                v.iter().sum::<u64>()
+            })
+        };
+        assert_eq!(sum, Ok(10));
+
+        // A plain read, from a frame with cleanups of its own, which are
+        // abandoned rather than unwound
+        let fault = unsafe {
+            plugin.try_access(ProtectionLevel::ReadWrite, |plugin| {
+                let mut v = Vec::new_in(plugin);
+                v.push(addr.read_volatile());
+                v
+            })
+        }
+        .unwrap_err();
+        assert_eq!(fault.access(), segvpanic::Access::Read);
+        assert_eq!(plugin.level(), ProtectionLevel::DenyAll);
+
+        let fault = unsafe {
+            plugin.try_access(ProtectionLevel::ReadWrite, |_| {
+                // A misbehaving plugin, granting itself access on the way
+                secrets.set_level(ProtectionLevel::ReadOnly);
+                addr.cast_mut().write_volatile(8)
+            })
+        }
+        .unwrap_err();
+        assert_eq!(fault.label(), secrets.id());
+        assert_eq!(fault.address(), addr as *const u8);
+        assert_eq!(fault.access(), segvpanic::Access::Write);
+        assert_eq!(secrets.level(), ProtectionLevel::DenyAll);
+        assert_eq!(plugin.level(), ProtectionLevel::DenyAll);
+        secrets.with_level(ProtectionLevel::ReadOnly, |_| assert_eq!(*secret, 7));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "segvpanic")]
+    fn try_access_passes_on_other_panics() -> Result<(), ProtectionError> {
+        let label = ProtectionLabel::create(ProtectionLevel::DenyAll)?;
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ =
+                unsafe { label.try_access(ProtectionLevel::ReadWrite, |_| panic!("plugin bug")) };
+        }))
+        .unwrap_err();
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"plugin bug"));
+        assert_eq!(label.level(), ProtectionLevel::DenyAll);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "allocator-api2"))]
+mod allocator_api2_test {
+    use allocator_api2::{boxed::Box, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn collections_on_stable() -> Result<(), ProtectionError> {
+        let label = ProtectionLabel::create(ProtectionLevel::ReadWrite)?;
+        let mut v = Vec::new_in(label.clone());
+        v.extend(0..1000u64);
+        let b = Box::new_in(v.iter().sum::<u64>(), label.clone());
+        assert_eq!(*b, 999 * 500);
+        assert_eq!(
+            label
+                .stats()
+                .bins
+                .iter()
+                .map(|b| b.live_slots)
+                .sum::<usize>(),
+            2
+        );
+        Ok(())
+    }
 }
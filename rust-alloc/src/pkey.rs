@@ -3,6 +3,8 @@
 
 use libc::{c_int, c_uint, c_void, size_t};
 
+use crate::LabelId;
+
 extern "C" {
     pub fn pkey_mprotect(addr: *mut c_void, len: size_t, prot: c_int, pkey: c_int) -> c_int;
     pub fn pkey_get(pkey: c_int) -> c_int;
@@ -14,3 +16,64 @@ extern "C" {
 
 pub const PKEY_DISABLE_ACCESS: c_int = 1;
 pub const PKEY_DISABLE_WRITE: c_int = 2;
+
+/// Restores the calling thread's rights to a key when dropped
+pub struct RestoreRights {
+    pkey: c_int,
+    rights: c_int,
+}
+
+impl RestoreRights {
+    /// Give the calling thread `rights` to `pkey` until the guard is dropped
+    pub fn set(pkey: c_int, rights: c_int) -> Self {
+        unsafe {
+            let restore = Self {
+                pkey,
+                rights: pkey_get(pkey),
+            };
+            pkey_set(pkey, rights);
+            restore
+        }
+    }
+}
+
+impl Drop for RestoreRights {
+    fn drop(&mut self) {
+        unsafe {
+            pkey_set(self.pkey, self.rights);
+        }
+    }
+}
+
+/// Run `func` with `rights` to `pkey`, restoring the calling thread's
+/// rights afterwards, including when `func` panics
+pub fn with_rights<F, O>(pkey: c_int, rights: c_int, func: F) -> O
+where
+    F: FnOnce() -> O,
+{
+    let _restore = RestoreRights::set(pkey, rights);
+    func()
+}
+
+/// Have faults on `pkey` raised as catchable panics and reported against
+/// `owner`, if `segvpanic` is enabled and installed
+pub fn register_faults(pkey: c_int, owner: LabelId) {
+    #[cfg(feature = "segvpanic")]
+    {
+        crate::registry::set_key_owner(pkey, Some(owner));
+        segvpanic::register_key(pkey);
+    }
+    #[cfg(not(feature = "segvpanic"))]
+    let _ = (pkey, owner);
+}
+
+/// Undo [`register_faults`] before `pkey` is freed
+pub fn unregister_faults(pkey: c_int) {
+    #[cfg(feature = "segvpanic")]
+    {
+        segvpanic::unregister_key(pkey);
+        crate::registry::set_key_owner(pkey, None);
+    }
+    #[cfg(not(feature = "segvpanic"))]
+    let _ = pkey;
+}
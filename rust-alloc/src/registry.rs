@@ -0,0 +1,202 @@
+//! Process wide map from address ranges to the labels which mapped them
+//!
+//! Page allocators record every range they map here, and remove it again
+//! when unmapping, so a pointer can be traced back to its label.
+
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::allocator::{MetaMap, MetaVec};
+
+/// Identifies a protection label for the life of the process
+///
+/// Unlike the protection key, which the kernel reuses once a label is
+/// dropped, an id is never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LabelId(u64);
+
+impl LabelId {
+    pub(crate) fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        LabelId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The name given to the label, if it was named and is still alive
+    pub fn name(&self) -> Option<String> {
+        NAMES
+            .lock()
+            .get(self)
+            .map(|name| String::from(as_str(name)))
+    }
+}
+
+/// Shows the label's name, if it has one, as well as the id
+impl fmt::Display for LabelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name} (label #{})", self.0),
+            None => write!(f, "label #{}", self.0),
+        }
+    }
+}
+
+type Ranges = MetaMap<usize, (usize, LabelId)>;
+
+/// Mapped ranges by start address, to their end and owner.  Ranges never
+/// overlap.  These are updated while mapping memory, so must not be
+/// allocated from a label.
+static RANGES: Mutex<Ranges> = Mutex::new(MetaMap::new());
+
+/// The names of live labels, for diagnostics.  Kept out of the heap, which
+/// a fault report may not be able to read.
+static NAMES: Mutex<MetaMap<LabelId, MetaVec<u8>>> = Mutex::new(MetaMap::new());
+
+fn as_str(name: &MetaVec<u8>) -> &str {
+    // Only ever copied from a `str`
+    unsafe { core::str::from_utf8_unchecked(name) }
+}
+
+pub(crate) fn set_name(owner: LabelId, name: &str) {
+    let mut bytes = MetaVec::with_capacity(name.len());
+    bytes.extend(name.bytes());
+    NAMES.lock().insert(owner, bytes);
+}
+
+/// Displays a label as [`LabelId`] does, but safe from a signal handler.
+/// The name is left out if it can't be had without waiting.
+#[cfg(feature = "segvpanic")]
+pub(crate) struct SignalSafe(pub LabelId);
+
+#[cfg(feature = "segvpanic")]
+impl fmt::Display for SignalSafe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = NAMES.try_lock();
+        match names.as_ref().and_then(|names| names.get(&self.0)) {
+            Some(name) => write!(f, "{} (label #{})", as_str(name), self.0 .0),
+            None => write!(f, "label #{}", self.0 .0),
+        }
+    }
+}
+
+/// The label each protection key was allocated for, as id + 1 or zero for
+/// none, so that faults can be reported against labels
+#[cfg(feature = "segvpanic")]
+static KEY_OWNERS: [AtomicU64; 16] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; 16]
+};
+
+#[cfg(feature = "segvpanic")]
+pub(crate) fn set_key_owner(pkey: libc::c_int, owner: Option<LabelId>) {
+    if let Some(slot) = usize::try_from(pkey).ok().and_then(|i| KEY_OWNERS.get(i)) {
+        slot.store(owner.map_or(0, |id| id.0 + 1), Ordering::SeqCst);
+    }
+}
+
+/// The label `pkey` was allocated for, if any
+#[cfg(feature = "segvpanic")]
+pub(crate) fn key_owner(pkey: libc::c_int) -> Option<LabelId> {
+    let slot = KEY_OWNERS.get(usize::try_from(pkey).ok()?)?;
+    slot.load(Ordering::SeqCst).checked_sub(1).map(LabelId)
+}
+
+/// Every protection key allocated for a label, with its owner
+#[cfg(feature = "segvpanic")]
+pub(crate) fn key_owners() -> impl Iterator<Item = (libc::c_int, LabelId)> {
+    (0..KEY_OWNERS.len() as libc::c_int).filter_map(|pkey| Some((pkey, key_owner(pkey)?)))
+}
+
+/// Record that `len` bytes from `start` belong to `owner`
+pub(crate) fn insert(start: *const u8, len: usize, owner: LabelId) {
+    let start = start as usize;
+    let mut ranges = RANGES.lock();
+    trim(&mut ranges, start, start + len);
+    ranges.insert(start, (start + len, owner));
+}
+
+/// Forget whoever owned the `len` bytes from `start`, splitting any range
+/// which only partly overlaps
+pub(crate) fn remove(start: *const u8, len: usize) {
+    let start = start as usize;
+    trim(&mut RANGES.lock(), start, start + len);
+}
+
+/// Forget every range belonging to `owner`, and its name
+pub(crate) fn remove_owner(owner: LabelId) {
+    RANGES.lock().retain(|_, &(_, id)| id != owner);
+    NAMES.lock().remove(&owner);
+}
+
+/// The label owning the byte at `addr`, if any
+pub(crate) fn lookup(addr: *const u8) -> Option<LabelId> {
+    owner_in(&RANGES.lock(), addr as usize)
+}
+
+/// As [`lookup`], but giving up rather than waiting if the ranges are
+/// locked.  Safe from a signal handler.
+#[cfg(feature = "segvpanic")]
+pub(crate) fn try_lookup(addr: *const u8) -> Option<LabelId> {
+    owner_in(&*RANGES.try_lock()?, addr as usize)
+}
+
+fn owner_in(ranges: &Ranges, addr: usize) -> Option<LabelId> {
+    let &(_, (end, owner)) = ranges.at_or_below(&addr)?;
+    (addr < end).then_some(owner)
+}
+
+fn trim(ranges: &mut Ranges, start: usize, end: usize) {
+    // A range starting before us may reach into, or right over, us
+    if let Some(&(before, (before_end, owner))) = ranges.below(&start) {
+        if before_end > start {
+            ranges.insert(before, (start, owner));
+            if before_end > end {
+                ranges.insert(end, (before_end, owner));
+            }
+        }
+    }
+    let mut inside = MetaVec::new();
+    inside.extend(ranges.range(&start, &end).iter().copied());
+    for &(s, (e, owner)) in &inside {
+        ranges.remove(&s);
+        if e > end {
+            ranges.insert(end, (e, owner));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ranges_split_and_merge() {
+        // Addresses nothing real will be mapped at
+        let (a, b) = (LabelId::next(), LabelId::next());
+        let base = 0x10usize as *const u8;
+        let at = |offset| unsafe { base.add(offset) };
+        insert(base, 0x300, a);
+        assert_eq!(lookup(at(0x2ff)), Some(a));
+        assert_eq!(lookup(at(0x300)), None);
+
+        // Punch a hole in the middle, then give part of it to someone else
+        remove(at(0x100), 0x100);
+        assert_eq!(lookup(at(0xff)), Some(a));
+        assert_eq!(lookup(at(0x100)), None);
+        assert_eq!(lookup(at(0x200)), Some(a));
+        insert(at(0x180), 0x100, b);
+        assert_eq!(lookup(at(0x17f)), None);
+        assert_eq!(lookup(at(0x180)), Some(b));
+        assert_eq!(lookup(at(0x27f)), Some(b));
+        assert_eq!(lookup(at(0x280)), Some(a));
+
+        remove_owner(a);
+        assert_eq!(lookup(at(0x0)), None);
+        assert_eq!(lookup(at(0x280)), None);
+        assert_eq!(lookup(at(0x200)), Some(b));
+        remove_owner(b);
+    }
+}
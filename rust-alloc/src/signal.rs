@@ -0,0 +1,169 @@
+//! Label levels inside signal handlers
+
+use core::sync::atomic::{AtomicI32, Ordering};
+
+use libc::c_int;
+
+use crate::pkey::RestoreRights;
+
+/// The rights each label's protection key gets under [`with_signal_pkru`],
+/// or -1 for keys no label holds
+static DEFAULT_RIGHTS: [AtomicI32; 16] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicI32 = AtomicI32::new(-1);
+    [NONE; 16]
+};
+
+/// Record the rights `pkey` gets under [`with_signal_pkru`], or `None` as it
+/// is being freed
+pub(crate) fn set_default_rights(pkey: c_int, rights: Option<c_int>) {
+    if let Some(slot) = usize::try_from(pkey)
+        .ok()
+        .and_then(|i| DEFAULT_RIGHTS.get(i))
+    {
+        slot.store(rights.unwrap_or(-1), Ordering::SeqCst);
+    }
+}
+
+/// Every label's protection key, with the rights of its default level
+pub(crate) fn default_rights() -> impl Iterator<Item = (c_int, c_int)> {
+    DEFAULT_RIGHTS
+        .iter()
+        .enumerate()
+        .filter_map(|(pkey, slot)| {
+            let rights = slot.load(Ordering::SeqCst);
+            (rights >= 0).then_some((pkey as c_int, rights))
+        })
+}
+
+/// Run `func` with every label at its default level, restoring the calling
+/// thread's levels afterwards, including when `func` panics
+///
+/// Linux doesn't run a signal handler with the rights of the code it
+/// interrupted, but with the kernel's default rights to every protection
+/// key.  On x86 that default denies all access to every key but key 0, so
+/// whatever level the interrupted thread had, every label is
+/// [`ProtectionLevel::DenyAll`] in the handler:
+///
+/// | Interrupted thread | Handler   | Handler, under `with_signal_pkru` |
+/// |--------------------|-----------|-----------------------------------|
+/// | `DenyAll`          | `DenyAll` | the label's default level         |
+/// | `ReadOnly`         | `DenyAll` | the label's default level         |
+/// | `ReadWrite`        | `DenyAll` | the label's default level         |
+///
+/// So a handler can't read memory its interrupted code could, and where the
+/// kernel's default has been loosened, such as through `x86/init_pkru` in
+/// debugfs, it can read memory the interrupted code had locked.  Running
+/// the handler under `with_signal_pkru` gives it the same levels whatever
+/// it interrupted, see [`ProtectionLabelBuilder::default_level`].  The
+/// global heap defaults to [`ProtectionLevel::ReadWrite`].
+///
+/// The default levels only apply inside `with_signal_pkru`.  The kernel
+/// knows nothing of them, so outside it a handler, like any handler
+/// installed with plain `sigaction`, runs with the kernel's `init_pkru`.
+///
+/// Safe to call from a signal handler, as long as `func` is.
+///
+/// [`ProtectionLabelBuilder::default_level`]: crate::ProtectionLabelBuilder::default_level
+/// [`ProtectionLevel::DenyAll`]: crate::ProtectionLevel::DenyAll
+/// [`ProtectionLevel::ReadWrite`]: crate::ProtectionLevel::ReadWrite
+pub fn with_signal_pkru<F, O>(func: F) -> O
+where
+    F: FnOnce() -> O,
+{
+    let mut restore: [Option<RestoreRights>; 16] = Default::default();
+    for (pkey, rights) in default_rights() {
+        restore[pkey as usize] = Some(RestoreRights::set(pkey, rights));
+    }
+    func()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pkey::pkey_get;
+    use crate::{ProtectionError, ProtectionLabel, ProtectionLevel};
+
+    static KEYS: [AtomicI32; 3] = [AtomicI32::new(0), AtomicI32::new(0), AtomicI32::new(0)];
+    /// Each key's rights in the handler, then under `with_signal_pkru`
+    static SEEN: [[AtomicI32; 3]; 2] = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const UNSEEN: [AtomicI32; 3] = [AtomicI32::new(-1), AtomicI32::new(-1), AtomicI32::new(-1)];
+        [UNSEEN; 2]
+    };
+
+    extern "C" fn handler(_: c_int) {
+        let record = |seen: &[AtomicI32; 3]| {
+            for (key, seen) in KEYS.iter().zip(seen) {
+                seen.store(
+                    unsafe { pkey_get(key.load(Ordering::SeqCst)) },
+                    Ordering::SeqCst,
+                );
+            }
+        };
+        record(&SEEN[0]);
+        with_signal_pkru(|| record(&SEEN[1]));
+    }
+
+    #[test]
+    fn levels_inside_handler() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        // The level of the thread, and the default level, of each label
+        let levels = [
+            (ReadWrite, DenyAll),
+            (ReadOnly, ReadOnly),
+            (DenyAll, ReadWrite),
+        ];
+        let labels = levels
+            .iter()
+            .map(|&(level, default)| {
+                ProtectionLabel::builder()
+                    .level(level)
+                    .default_level(default)
+                    .create()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, label) in KEYS.iter().zip(&labels) {
+            key.store(label.key(), Ordering::SeqCst);
+        }
+        unsafe {
+            let previous = libc::signal(libc::SIGUSR1, handler as *const () as libc::sighandler_t);
+            libc::raise(libc::SIGUSR1);
+            libc::signal(libc::SIGUSR1, previous);
+        }
+
+        let seen = |i: usize| {
+            SEEN[i]
+                .iter()
+                .map(|rights| ProtectionLevel::from_flags(rights.load(Ordering::SeqCst)))
+                .collect::<Vec<_>>()
+        };
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(seen(0), [DenyAll; 3]);
+        assert_eq!(seen(1), levels.map(|(_, default)| default));
+        // And the thread is back to its own levels
+        for (label, (level, _)) in labels.iter().zip(levels) {
+            assert_eq!(label.level(), level);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn restores_after_panic() -> Result<(), ProtectionError> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use ProtectionLevel::*;
+        let label = ProtectionLabel::builder()
+            .level(ReadOnly)
+            .default_level(ReadWrite)
+            .create()?;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            with_signal_pkru(|| {
+                assert_eq!(label.level(), ReadWrite);
+                panic!("unwinding out of with_signal_pkru");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(label.level(), ReadOnly);
+        Ok(())
+    }
+}
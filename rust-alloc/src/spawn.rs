@@ -0,0 +1,143 @@
+//! Spawning threads which start with known levels
+//!
+//! A new thread inherits the levels of the thread spawning it, so a thread
+//! spawned within [`ProtectionLabel::with_level`] keeps the raised level
+//! for the whole of its life.  Threads spawned from here start with every
+//! label at its default level instead, see
+//! [`ProtectionLabelBuilder::default_level`], then with the levels given.
+//!
+//! [`ProtectionLabelBuilder::default_level`]: crate::ProtectionLabelBuilder::default_level
+
+use std::io;
+use std::thread::{Builder, JoinHandle};
+
+use crate::pkey::pkey_set;
+use crate::signal::default_rights;
+use crate::{ProtectionLabel, ProtectionLevel};
+
+/// The levels a new thread starts with, keeping the labels alive until it
+/// has set them
+struct Levels(Vec<(ProtectionLabel, ProtectionLevel)>);
+
+impl Levels {
+    fn new(levels: &[(&ProtectionLabel, ProtectionLevel)]) -> Self {
+        Self(
+            levels
+                .iter()
+                .map(|&(label, level)| (label.clone(), level))
+                .collect(),
+        )
+    }
+
+    fn apply(self) {
+        for (pkey, rights) in default_rights() {
+            unsafe {
+                pkey_set(pkey, rights);
+            }
+        }
+        for (label, level) in self.0 {
+            unsafe { label.set_level(level) };
+        }
+    }
+}
+
+/// Spawn a thread starting with every label at its default level, other
+/// than those in `levels`, as [`std::thread::spawn`]
+///
+/// With the `segvpanic` feature the thread is also given an alternate
+/// signal stack, see `segvpanic::install_altstack`.
+///
+/// # Panics
+///
+/// If the thread can't be spawned, as [`std::thread::spawn`].
+pub fn protected_spawn<F, T>(
+    levels: &[(&ProtectionLabel, ProtectionLevel)],
+    func: F,
+) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new()
+        .spawn_protected(levels, func)
+        .expect("failed to spawn thread")
+}
+
+/// Spawning threads with known levels from a [`std::thread::Builder`]
+pub trait BuilderExt {
+    /// As [`protected_spawn`], with the builder's name and stack size
+    fn spawn_protected<F, T>(
+        self,
+        levels: &[(&ProtectionLabel, ProtectionLevel)],
+        func: F,
+    ) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+impl BuilderExt for Builder {
+    fn spawn_protected<F, T>(
+        self,
+        levels: &[(&ProtectionLabel, ProtectionLevel)],
+        func: F,
+    ) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let levels = Levels::new(levels);
+        self.spawn(move || {
+            #[cfg(feature = "segvpanic")]
+            segvpanic::install_altstack().expect("no alternate signal stack for the new thread");
+            levels.apply();
+            func()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::ProtectionError;
+
+    #[test]
+    fn spawned_threads_drop_raised_levels() -> Result<(), ProtectionError> {
+        use ProtectionLevel::*;
+        let secrets = ProtectionLabel::create(DenyAll)?;
+        let plugin = ProtectionLabel::create(DenyAll)?;
+        let shared = ProtectionLabel::builder()
+            .default_level(ReadOnly)
+            .create()?;
+        let level = |label: &ProtectionLabel| {
+            let label = label.clone();
+            move || label.level()
+        };
+
+        secrets.with_level(ReadWrite, |_| {
+            // A plain thread inherits the raised level
+            assert_eq!(thread::spawn(level(&secrets)).join().unwrap(), ReadWrite);
+            let levels = [&secrets, &plugin, &shared].map(|label| {
+                let level = level(label);
+                protected_spawn(&[(&plugin, ReadWrite)], level)
+                    .join()
+                    .unwrap()
+            });
+            assert_eq!(levels, [DenyAll, ReadWrite, ReadOnly]);
+        });
+
+        let named = Builder::new()
+            .name("plugin".into())
+            .spawn_protected(&[(&secrets, ReadOnly)], {
+                let level = level(&secrets);
+                move || (thread::current().name().map(String::from), level())
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(named, (Some("plugin".into()), ReadOnly));
+        Ok(())
+    }
+}
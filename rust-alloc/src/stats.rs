@@ -0,0 +1,49 @@
+//! Allocation statistics
+//!
+//! These are snapshots of the counters maintained by the allocator for
+//! a single protection label.  The counters are updated with relaxed
+//! atomics so a snapshot taken while other threads are allocating may
+//! be very slightly inconsistent between fields.
+
+use alloc::vec::Vec;
+
+/// Statistics for a single size class bin
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BinStats {
+    /// The size of each slot in this bin
+    pub slot_size: usize,
+    /// Slots currently handed out to callers
+    pub live_slots: usize,
+    /// Slots sitting on the bin's free list, quarantined slots are counted
+    /// in neither this nor `live_slots`
+    pub free_slots: usize,
+    /// Chunks mapped for this bin
+    pub chunks: usize,
+}
+
+/// Statistics for a protection label's allocator
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LabelStats {
+    /// Per-bin statistics, smallest slot size first
+    pub bins: Vec<BinStats>,
+    /// Allocations too big for any bin, which are mapped directly
+    pub large_allocations: usize,
+    /// Bytes requested by the large allocations
+    pub large_bytes: usize,
+    /// Total bytes currently mapped for this label, chunks and large
+    /// allocations alike
+    pub mapped_bytes: usize,
+    /// Bytes of `mapped_bytes` backed by huge pages from the hugetlbfs pool
+    pub hugetlb_bytes: usize,
+    /// Bytes of `mapped_bytes` the kernel accepted the advice to back with
+    /// transparent huge pages.  This is what was advised, not what was
+    /// obtained: the kernel may still use normal pages, for example when
+    /// transparent huge pages are disabled or memory is fragmented.
+    pub transparent_huge_page_bytes: usize,
+    /// Bytes of address space reserved for the label's arena, if it has one
+    pub reserved_bytes: usize,
+    /// Freed slots waiting in quarantine before they can be reused
+    pub quarantined_slots: usize,
+    /// Bytes of slots waiting in quarantine
+    pub quarantined_bytes: usize,
+}
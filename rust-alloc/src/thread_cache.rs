@@ -0,0 +1,139 @@
+//! Per-thread caches of free slots
+//!
+//! Every bin's free list is shared, so many threads allocating from one
+//! label will contend on the same cache lines.  With thread caches enabled
+//! each thread keeps
+//! a small stack of free slots per bin, per label, which it can allocate
+//! from and free to without touching the bin at all.  The stacks are
+//! refilled from, and flushed back to, the bins in batches.
+//!
+//! Cached slots count as live in the label's statistics.  A thread's caches
+//! are flushed back to their labels when the thread exits.  Labels which
+//! track allocations don't use thread caches, since nothing could tell a
+//! cached slot from a live one while walking.
+
+use std::{
+    alloc::Layout,
+    cell::RefCell,
+    ptr::NonNull,
+    sync::{Arc, Weak},
+};
+
+use crate::{LabelId, ProtectionLabelInner, ProtectionLevel};
+
+/// Roughly how many bytes of slots a thread may cache per bin
+const CACHE_BYTES: usize = 0x10000;
+/// The most slots a thread may cache per bin
+const MAX_CACHED: usize = 64;
+
+fn capacity(slot_size: usize) -> usize {
+    (CACHE_BYTES / slot_size).clamp(1, MAX_CACHED)
+}
+
+struct LabelCache {
+    id: LabelId,
+    label: Weak<ProtectionLabelInner>,
+    bins: Vec<Vec<*mut u8>>,
+}
+
+impl LabelCache {
+    fn bin(&mut self, index: usize) -> &mut Vec<*mut u8> {
+        if self.bins.len() <= index {
+            self.bins.resize_with(index + 1, Vec::new);
+        }
+        &mut self.bins[index]
+    }
+}
+
+impl Drop for LabelCache {
+    fn drop(&mut self) {
+        // If the label has gone, so has the memory behind the cached slots
+        if let Some(label) = self.label.upgrade() {
+            label.with_level(ProtectionLevel::ReadWrite, || {
+                for (index, slots) in self.bins.iter().enumerate() {
+                    if !slots.is_empty() {
+                        unsafe { label.alloc.flush(index, slots) };
+                    }
+                }
+            });
+        }
+    }
+}
+
+thread_local! {
+    static CACHES: RefCell<Vec<LabelCache>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `func` with this thread's cache for `label`, if it's available
+fn with_cache<F, O>(label: &Arc<ProtectionLabelInner>, func: F) -> Option<O>
+where
+    F: FnOnce(&mut LabelCache) -> O,
+{
+    CACHES
+        .try_with(|caches| {
+            let mut caches = caches.try_borrow_mut().ok()?;
+            let at = match caches.iter().position(|c| c.id == label.alloc.id()) {
+                Some(at) => at,
+                None => {
+                    // Good time to forget about caches of dropped labels
+                    caches.retain(|c| c.label.strong_count() > 0);
+                    caches.push(LabelCache {
+                        id: label.alloc.id(),
+                        label: Arc::downgrade(label),
+                        bins: Vec::new(),
+                    });
+                    caches.len() - 1
+                }
+            };
+            Some(func(&mut caches[at]))
+        })
+        .ok()
+        .flatten()
+}
+
+/// Allocate from this thread's cache for `label`, returning `None` if the
+/// allocation should go to the label's bins instead
+pub(crate) fn allocate(label: &Arc<ProtectionLabelInner>, layout: Layout) -> Option<NonNull<[u8]>> {
+    let index = label.alloc.cache_index(layout)?;
+    with_cache(label, |cache| {
+        let slots = cache.bin(index);
+        if slots.is_empty() {
+            let count = capacity(label.alloc.slot_size(index)) / 2;
+            unsafe { label.alloc.refill(index, slots, count.max(1)) };
+        }
+        let ptr = NonNull::new(slots.pop()?)?;
+        Some(NonNull::slice_from_raw_parts(
+            ptr,
+            layout.pad_to_align().size(),
+        ))
+    })
+    .flatten()
+}
+
+/// Free to this thread's cache for `label`, returning `false` if the
+/// allocation should go back to the label's bins instead
+///
+/// # Safety
+/// `ptr` must have been allocated from `label` with `layout`
+pub(crate) unsafe fn deallocate(
+    label: &Arc<ProtectionLabelInner>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+) -> bool {
+    let Some(index) = label.alloc.cache_index(layout) else {
+        return false;
+    };
+    with_cache(label, |cache| {
+        let capacity = capacity(label.alloc.slot_size(index));
+        let slots = cache.bin(index);
+        if slots.len() >= capacity {
+            let keep = capacity / 2;
+            label.with_level(ProtectionLevel::ReadWrite, || {
+                label.alloc.flush(index, &slots[keep..]);
+            });
+            slots.truncate(keep);
+        }
+        slots.push(ptr.as_ptr());
+    })
+    .is_some()
+}
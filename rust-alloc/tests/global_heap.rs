@@ -0,0 +1,57 @@
+//! The whole test binary runs on a labelled heap, which is the only way to
+//! check the allocator doesn't recurse into itself
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::collections::HashMap;
+
+use rsbmalloc::{LabelledHeap, ProtectionLabel, ProtectionLevel};
+
+#[global_allocator]
+static HEAP: LabelledHeap = LabelledHeap::new();
+
+#[test]
+fn everything_is_labelled() {
+    let mut map = HashMap::new();
+    for i in 0..10_000 {
+        map.insert(i, i.to_string());
+    }
+    let large = vec![7u8; 0x100000];
+    assert_eq!(map[&1234], "1234");
+    assert_eq!(
+        large.iter().map(|&b| b as usize).sum::<usize>(),
+        7 * 0x100000
+    );
+
+    let stats = HEAP.stats();
+    assert!(stats.bins.iter().map(|b| b.live_slots).sum::<usize>() >= 10_000);
+    assert!(stats.large_allocations >= 1);
+    assert_eq!(ProtectionLabel::owner_of(large.as_ptr()), Some(HEAP.id()));
+    assert_eq!(ProtectionLabel::owner_of(map[&1].as_ptr()), Some(HEAP.id()));
+}
+
+#[test]
+fn threads_share_the_heap() {
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            std::thread::spawn(move || {
+                let v: Vec<Box<usize>> = (0..1000).map(|i| Box::new(t * 1000 + i)).collect();
+                v.iter().map(|b| **b).sum::<usize>()
+            })
+        })
+        .collect();
+    let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(total, (0..8000).sum());
+}
+
+#[test]
+fn allocating_without_access() {
+    let layout = Layout::new::<[u64; 4]>();
+    assert_eq!(HEAP.level(), ProtectionLevel::ReadWrite);
+    // The allocator can still allocate, even though the caller can't touch
+    // what it gets back
+    let ptr = HEAP.with_level(ProtectionLevel::DenyAll, || unsafe { alloc(layout) });
+    assert!(!ptr.is_null());
+    unsafe { ptr.write_bytes(1, layout.size()) };
+    assert_eq!(ProtectionLabel::owner_of(ptr), Some(HEAP.id()));
+    HEAP.with_level(ProtectionLevel::DenyAll, || unsafe { dealloc(ptr, layout) });
+}
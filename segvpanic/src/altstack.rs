@@ -0,0 +1,100 @@
+//! Alternate signal stacks, so a thread which has run out of stack can
+//! still handle its fault
+
+use std::cell::RefCell;
+use std::io;
+use std::ptr;
+use std::thread::{self, JoinHandle};
+
+use libc::c_void;
+
+/// The usable size of the alternate stacks set up by [`install_altstack`],
+/// enough for the fault hook to write a backtrace
+pub const ALTSTACK_SIZE: usize = 64 * 1024;
+
+/// The mapping of a thread's alternate stack, with a guard page below it
+struct AltStack {
+    mapping: *mut c_void,
+    len: usize,
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        unsafe {
+            let mut disable: libc::stack_t = std::mem::zeroed();
+            disable.ss_flags = libc::SS_DISABLE;
+            libc::sigaltstack(&disable, ptr::null_mut());
+            libc::munmap(self.mapping, self.len);
+        }
+    }
+}
+
+thread_local! {
+    static ALTSTACK: RefCell<Option<AltStack>> = const { RefCell::new(None) };
+}
+
+/// Give the calling thread an alternate signal stack of [`ALTSTACK_SIZE`]
+/// bytes, which is freed when the thread exits.  A thread which already
+/// has one from here keeps it.
+///
+/// Without an alternate stack a thread which overflows its stack can't run
+/// any signal handler, so dies from the fault.
+///
+/// The stack isn't protected by a key, as handlers start out denied every
+/// key but key 0, and would fault on their first push.
+pub fn install_altstack() -> io::Result<()> {
+    ALTSTACK.with(|altstack| {
+        let mut altstack = altstack.borrow_mut();
+        if altstack.is_none() {
+            *altstack = Some(map_altstack()?);
+        }
+        Ok(())
+    })
+}
+
+fn map_altstack() -> io::Result<AltStack> {
+    unsafe {
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let len = ALTSTACK_SIZE + page;
+        let mapping = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+            -1,
+            0,
+        );
+        if mapping == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let base = (mapping as *mut u8).add(page) as *mut c_void;
+        let protected = libc::mprotect(base, ALTSTACK_SIZE, libc::PROT_READ | libc::PROT_WRITE);
+        let new = libc::stack_t {
+            ss_sp: base,
+            ss_flags: 0,
+            ss_size: ALTSTACK_SIZE,
+        };
+        if protected != 0 || libc::sigaltstack(&new, ptr::null_mut()) != 0 {
+            let error = io::Error::last_os_error();
+            libc::munmap(mapping, len);
+            return Err(error);
+        }
+        Ok(AltStack { mapping, len })
+    }
+}
+
+/// Spawn a thread with an alternate signal stack, as [`std::thread::spawn`]
+///
+/// # Panics
+///
+/// If the alternate stack can't be set up, in the new thread.
+pub fn spawn<F, T>(func: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::spawn(move || {
+        install_altstack().expect("no alternate signal stack for the new thread");
+        func()
+    })
+}
@@ -0,0 +1,229 @@
+use core::ptr;
+
+use crate::Access;
+
+/// Marks the end of the records in a signal frame
+const END_MAGIC: u32 = 0;
+/// A record pointing to more records, outside the frame's reserved space
+const EXTRA_MAGIC: u32 = 0x4558_5401;
+/// A record holding the syndrome of the fault
+const ESR_MAGIC: u32 = 0x4553_5201;
+/// A record holding the interrupted thread's POR_EL0
+const POE_MAGIC: u32 = 0x504f_4530;
+
+/// Bits of a key's permissions in POR_EL0
+const POE_R: u64 = 1;
+const POE_W: u64 = 4;
+/// The number of keys Linux hands out on aarch64
+const KEYS: u32 = 8;
+
+/// The header of each record in the reserved space of the signal frame
+#[repr(C)]
+struct Record {
+    magic: u32,
+    size: u32,
+}
+
+/// The record following `EXTRA_MAGIC`
+#[repr(C)]
+struct ExtraRecord {
+    head: Record,
+    data: u64,
+    size: u32,
+}
+
+/// The payload of the record tagged `magic`, if the frame has one
+unsafe fn find_record(context: &libc::ucontext_t, magic: u32) -> Option<u64> {
+    // The records start at the first 16 byte boundary after pstate
+    let after_pstate = ptr::addr_of!(context.uc_mcontext.pstate).add(1) as usize;
+    let mut record = ((after_pstate + 15) & !15) as *const Record;
+    // Each record is at least a header, which bounds the walk
+    for _ in 0..4096 / 8 {
+        match (*record).magic {
+            END_MAGIC => return None,
+            found if found == magic => return Some((record.add(1) as *const u64).read()),
+            EXTRA_MAGIC => {
+                let extra = &*(record as *const ExtraRecord);
+                if extra.size == 0 {
+                    return None;
+                }
+                record = extra.data as *const Record;
+            }
+            _ => {
+                let size = (*record).size as usize;
+                if size < 8 {
+                    return None;
+                }
+                record = (record as *const u8).add(size) as *const Record;
+            }
+        }
+    }
+    None
+}
+
+/// How the faulting instruction tried to access memory
+pub(crate) fn access(context: &libc::ucontext_t) -> Access {
+    /// Exception classes of data aborts, from a lower and the current level
+    const EC_DABT: [u64; 2] = [0x24, 0x25];
+    /// Set in the syndrome of a data abort caused by a write
+    const ESR_WNR: u64 = 1 << 6;
+
+    match unsafe { find_record(context, ESR_MAGIC) } {
+        Some(esr) if EC_DABT.contains(&(esr >> 26 & 0x3f)) && esr & ESR_WNR != 0 => Access::Write,
+        _ => Access::Read,
+    }
+}
+
+/// The interrupted thread's POR_EL0, from the signal frame, translated to
+/// the layout of x86's PKRU.  The handler itself runs with the kernel's
+/// default rights instead.
+pub(crate) unsafe fn interrupted_rights(context: &libc::ucontext_t) -> Option<u32> {
+    let por = find_record(context, POE_MAGIC)?;
+    let mut rights = 0;
+    for key in 0..KEYS {
+        let perms = por >> (4 * key);
+        if perms & POE_R == 0 {
+            rights |= 1 << (2 * key);
+        }
+        if perms & POE_W == 0 {
+            rights |= 2 << (2 * key);
+        }
+    }
+    Some(rights)
+}
+
+/// Make the interrupted thread resume in [`segvpanic`](crate::segvpanic),
+/// through a trampoline frame describing the faulting instruction
+///
+/// The trampoline frame holds the faulting instruction's address, and the
+/// link register and stack pointer at the fault, which the trampoline's CFI
+/// restores when unwinding into the faulting frame.  Keeping the link
+/// register lets the unwinder get past a leaf function which never saved
+/// its return address.
+pub(crate) unsafe fn redirect(context: &mut libc::ucontext_t) {
+    let mcontext = &mut context.uc_mcontext;
+    let sp = mcontext.sp as usize;
+    let frame = ((sp & !15) as *mut u64).sub(4);
+    frame.write(mcontext.pc);
+    frame.add(1).write(mcontext.regs[30]);
+    frame.add(2).write(sp as u64);
+    mcontext.sp = frame as u64;
+    mcontext.pc = segvpanic_trampoline as *const () as u64;
+}
+
+/// What `segvpanic_recover_call` saves to return to: the callee saved
+/// registers x19 to x30, the stack pointer, then d8 to d15
+pub(crate) type Registers = [u64; 21];
+
+/// Make the interrupted thread return from the `segvpanic_recover_call`
+/// which saved `regs`, abandoning every frame it has pushed since
+pub(crate) unsafe fn resume(context: &mut libc::ucontext_t, regs: *const Registers) {
+    let mcontext = &mut context.uc_mcontext;
+    mcontext.regs[0] = regs as u64;
+    mcontext.pc = segvpanic_recover_resume as *const () as u64;
+}
+
+extern "C" {
+    fn segvpanic_trampoline();
+    fn segvpanic_recover_resume();
+}
+
+extern "C-unwind" {
+    /// Save the registers to return with to `regs`, then call `func(data)`,
+    /// returning false, or true once [`resume`] has sent a faulting thread
+    /// back here
+    pub(crate) fn segvpanic_recover_call(
+        regs: *mut Registers,
+        func: unsafe extern "C-unwind" fn(*mut libc::c_void),
+        data: *mut libc::c_void,
+    ) -> bool;
+}
+
+// The link register is restored for the faulting frame, so the faulting
+// address is handed back through x17 instead, which is free for the linker
+// to clobber at any call.  Marked as a signal frame, so the unwinder looks up
+// the faulting instruction itself, rather than the one before it as for a
+// return address.
+core::arch::global_asm!(
+    ".pushsection .text.segvpanic_trampoline,\"ax\",%progbits",
+    ".globl segvpanic_trampoline",
+    ".hidden segvpanic_trampoline",
+    ".type segvpanic_trampoline, %function",
+    ".p2align 2",
+    "segvpanic_trampoline:",
+    ".cfi_startproc",
+    ".cfi_signal_frame",
+    ".cfi_return_column 17",
+    ".cfi_def_cfa 31, 32",
+    ".cfi_offset 17, -32",
+    ".cfi_offset 30, -24",
+    ".cfi_offset 31, -16",
+    "bl {segvpanic}",
+    "brk #1",
+    ".cfi_endproc",
+    ".size segvpanic_trampoline, . - segvpanic_trampoline",
+    ".popsection",
+    segvpanic = sym crate::segvpanic,
+);
+
+// The call is a frame of its own, so a panic from `func` unwinds through it
+// as usual.  Resuming restores the registers a call must preserve, the link
+// register and stack pointer among them, and returns true.
+core::arch::global_asm!(
+    ".pushsection .text.segvpanic_recover,\"ax\",%progbits",
+    ".globl segvpanic_recover_call",
+    ".hidden segvpanic_recover_call",
+    ".type segvpanic_recover_call, %function",
+    ".p2align 2",
+    "segvpanic_recover_call:",
+    ".cfi_startproc",
+    "stp x19, x20, [x0, #0]",
+    "stp x21, x22, [x0, #16]",
+    "stp x23, x24, [x0, #32]",
+    "stp x25, x26, [x0, #48]",
+    "stp x27, x28, [x0, #64]",
+    "stp x29, x30, [x0, #80]",
+    "mov x9, sp",
+    "str x9, [x0, #96]",
+    "stp d8, d9, [x0, #104]",
+    "stp d10, d11, [x0, #120]",
+    "stp d12, d13, [x0, #136]",
+    "stp d14, d15, [x0, #152]",
+    "stp x29, x30, [sp, #-16]!",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset 29, -16",
+    ".cfi_offset 30, -8",
+    "mov x29, sp",
+    "mov x0, x2",
+    "blr x1",
+    "ldp x29, x30, [sp], #16",
+    ".cfi_def_cfa_offset 0",
+    ".cfi_restore 29",
+    ".cfi_restore 30",
+    "mov w0, #0",
+    "ret",
+    ".cfi_endproc",
+    ".size segvpanic_recover_call, . - segvpanic_recover_call",
+    "",
+    ".globl segvpanic_recover_resume",
+    ".hidden segvpanic_recover_resume",
+    ".type segvpanic_recover_resume, %function",
+    ".p2align 2",
+    "segvpanic_recover_resume:",
+    "ldp x19, x20, [x0, #0]",
+    "ldp x21, x22, [x0, #16]",
+    "ldp x23, x24, [x0, #32]",
+    "ldp x25, x26, [x0, #48]",
+    "ldp x27, x28, [x0, #64]",
+    "ldp x29, x30, [x0, #80]",
+    "ldr x9, [x0, #96]",
+    "mov sp, x9",
+    "ldp d8, d9, [x0, #104]",
+    "ldp d10, d11, [x0, #120]",
+    "ldp d12, d13, [x0, #136]",
+    "ldp d14, d15, [x0, #152]",
+    "mov w0, #1",
+    "ret",
+    ".size segvpanic_recover_resume, . - segvpanic_recover_resume",
+    ".popsection",
+);
@@ -0,0 +1,20 @@
+//! The parts of handling a fault which depend on the architecture: reading
+//! the signal frame, and redirecting the thread to the panic trampoline or
+//! back to a recovery point
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64::{
+    access, interrupted_rights, redirect, resume, segvpanic_recover_call, Registers,
+};
+#[cfg(target_arch = "x86_64")]
+pub(crate) use x86_64::{
+    access, interrupted_rights, redirect, resume, segvpanic_recover_call, Registers,
+};
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("segvpanic only knows how to redirect a faulting thread on x86_64 and aarch64");
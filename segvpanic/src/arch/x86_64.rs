@@ -0,0 +1,159 @@
+use crate::Access;
+
+/// How the faulting instruction tried to access memory
+pub(crate) fn access(context: &libc::ucontext_t) -> Access {
+    // Bit 1 of the page fault error code is set for writes
+    if context.uc_mcontext.gregs[libc::REG_ERR as usize] & 2 != 0 {
+        Access::Write
+    } else {
+        Access::Read
+    }
+}
+
+/// The interrupted thread's PKRU, from the XSAVE area of the signal frame.
+/// The handler itself runs with the kernel's default rights instead.
+pub(crate) unsafe fn interrupted_rights(context: &libc::ucontext_t) -> Option<u32> {
+    /// The XSAVE state component holding PKRU
+    const PKRU_COMPONENT: u32 = 9;
+    /// Marks a signal frame's FP state as extended with an XSAVE area
+    const FP_XSTATE_MAGIC1: u32 = 0x4650_5853;
+
+    let xsave = context.uc_mcontext.fpregs as *const u8;
+    if xsave.is_null() || (xsave.add(464) as *const u32).read_unaligned() != FP_XSTATE_MAGIC1 {
+        return None;
+    }
+    let leaf = core::arch::x86_64::__cpuid_count(0xd, PKRU_COMPONENT);
+    if leaf.eax < 4 {
+        // The CPU doesn't save PKRU with XSAVE
+        return None;
+    }
+    let xstate_bv = (xsave.add(512) as *const u64).read_unaligned();
+    if xstate_bv & (1 << PKRU_COMPONENT) == 0 {
+        // Still in its initial state, all rights granted
+        return Some(0);
+    }
+    Some((xsave.add(leaf.ebx as usize) as *const u32).read_unaligned())
+}
+
+/// How far below the stack pointer a leaf function may keep data without
+/// moving the stack pointer
+const RED_ZONE: usize = 128;
+
+/// Make the interrupted thread resume in [`segvpanic`](crate::segvpanic),
+/// through a trampoline frame describing the faulting instruction
+///
+/// The trampoline frame goes below the red zone, so nothing the faulting
+/// function kept there is overwritten.  It holds the faulting instruction's
+/// address and the stack pointer at the fault, which the trampoline's CFI
+/// restores when unwinding into the faulting frame.
+pub(crate) unsafe fn redirect(context: &mut libc::ucontext_t) {
+    let gregs = &mut context.uc_mcontext.gregs;
+    let rsp = gregs[libc::REG_RSP as usize] as usize;
+    let frame = (((rsp - RED_ZONE) & !15) as *mut u64).sub(2);
+    frame.write(gregs[libc::REG_RIP as usize] as u64);
+    frame.add(1).write(rsp as u64);
+    gregs[libc::REG_RSP as usize] = frame as i64;
+    gregs[libc::REG_RIP as usize] = segvpanic_trampoline as *const () as i64;
+}
+
+/// What `segvpanic_recover_call` saves to return to: the callee saved
+/// registers, then the stack pointer and address to return with
+pub(crate) type Registers = [u64; 8];
+
+/// Make the interrupted thread return from the `segvpanic_recover_call`
+/// which saved `regs`, abandoning every frame it has pushed since
+pub(crate) unsafe fn resume(context: &mut libc::ucontext_t, regs: *const Registers) {
+    let gregs = &mut context.uc_mcontext.gregs;
+    gregs[libc::REG_RDI as usize] = regs as i64;
+    gregs[libc::REG_RIP as usize] = segvpanic_recover_resume as *const () as i64;
+}
+
+extern "C" {
+    fn segvpanic_trampoline();
+    fn segvpanic_recover_resume();
+}
+
+extern "C-unwind" {
+    /// Save the registers to return with to `regs`, then call `func(data)`,
+    /// returning false, or true once [`resume`] has sent a faulting thread
+    /// back here
+    pub(crate) fn segvpanic_recover_call(
+        regs: *mut Registers,
+        func: unsafe extern "C-unwind" fn(*mut libc::c_void),
+        data: *mut libc::c_void,
+    ) -> bool;
+}
+
+// Marked as a signal frame, so the unwinder looks up the faulting
+// instruction itself, rather than the one before it as for a return address
+core::arch::global_asm!(
+    ".pushsection .text.segvpanic_trampoline,\"ax\",@progbits",
+    ".globl segvpanic_trampoline",
+    ".hidden segvpanic_trampoline",
+    ".type segvpanic_trampoline, @function",
+    ".p2align 4",
+    "segvpanic_trampoline:",
+    ".cfi_startproc",
+    ".cfi_signal_frame",
+    ".cfi_def_cfa %rsp, 16",
+    ".cfi_offset %rip, -16",
+    ".cfi_offset %rsp, -8",
+    "call {segvpanic}@PLT",
+    "ud2",
+    ".cfi_endproc",
+    ".size segvpanic_trampoline, . - segvpanic_trampoline",
+    ".popsection",
+    segvpanic = sym crate::segvpanic,
+    options(att_syntax),
+);
+
+// The call is a frame of its own, so a panic from `func` unwinds through it
+// as usual.  Resuming restores the registers a call must preserve, with the
+// stack pointer and return address as they were, and returns true.
+core::arch::global_asm!(
+    ".pushsection .text.segvpanic_recover,\"ax\",@progbits",
+    ".globl segvpanic_recover_call",
+    ".hidden segvpanic_recover_call",
+    ".type segvpanic_recover_call, @function",
+    ".p2align 4",
+    "segvpanic_recover_call:",
+    ".cfi_startproc",
+    "movq %rbx, 0(%rdi)",
+    "movq %rbp, 8(%rdi)",
+    "movq %r12, 16(%rdi)",
+    "movq %r13, 24(%rdi)",
+    "movq %r14, 32(%rdi)",
+    "movq %r15, 40(%rdi)",
+    "leaq 8(%rsp), %rax",
+    "movq %rax, 48(%rdi)",
+    "movq (%rsp), %rax",
+    "movq %rax, 56(%rdi)",
+    "subq $8, %rsp",
+    ".cfi_adjust_cfa_offset 8",
+    "movq %rdx, %rdi",
+    "callq *%rsi",
+    "addq $8, %rsp",
+    ".cfi_adjust_cfa_offset -8",
+    "xorl %eax, %eax",
+    "retq",
+    ".cfi_endproc",
+    ".size segvpanic_recover_call, . - segvpanic_recover_call",
+    "",
+    ".globl segvpanic_recover_resume",
+    ".hidden segvpanic_recover_resume",
+    ".type segvpanic_recover_resume, @function",
+    ".p2align 4",
+    "segvpanic_recover_resume:",
+    "movq 0(%rdi), %rbx",
+    "movq 8(%rdi), %rbp",
+    "movq 16(%rdi), %r12",
+    "movq 24(%rdi), %r13",
+    "movq 32(%rdi), %r14",
+    "movq 40(%rdi), %r15",
+    "movq 48(%rdi), %rsp",
+    "movl $1, %eax",
+    "jmpq *56(%rdi)",
+    ".size segvpanic_recover_resume, . - segvpanic_recover_resume",
+    ".popsection",
+    options(att_syntax),
+);
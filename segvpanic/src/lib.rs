@@ -0,0 +1,703 @@
+//! Turn protection key faults into panics
+//!
+//! Once [`install`]ed, a SIGSEGV raised by a protection key which has been
+//! [`register_key`]ed makes the faulting thread panic with a
+//! [`SegmentationViolation`] payload at the faulting instruction, as if it
+//! had called [`panic_any`] itself, so the fault can be caught with
+//! [`catch_fault`].  Any other SIGSEGV, such as a null dereference, still
+//! goes to the previous or default handler.
+//!
+//! ```no_run
+//! use segvpanic::SegmentationViolation;
+//!
+//! # let (key, guarded): (libc::c_int, *const u64) = (1, std::ptr::null());
+//! // `guarded` was mapped with `pkey_mprotect` under `key`, which denies access
+//! segvpanic::register_key(key);
+//! segvpanic::install();
+//! let fault = segvpanic::catch_fault(|| unsafe { guarded.read_volatile() }).unwrap_err();
+//! let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+//! assert_eq!(fault.address(), guarded as *const u8);
+//! assert_eq!(fault.key(), key);
+//! ```
+//!
+//! Unwinding starts at the faulting instruction, but the compiler only
+//! expects unwinding from calls, and drops cleanups and even
+//! [`std::panic::catch_unwind`] around code which can't panic.  Worse, a
+//! faulting frame which does have cleanups, say a drop guard inlined into
+//! the closure, has no entry for the faulting instruction, and unwinding
+//! from it aborts the process.  So a closure passed to [`catch_fault`] must
+//! not hold drop guards, nor have faulting code inlined into frames with
+//! cleanups, which in release builds is hard to rule out.
+//!
+//! [`recover`] doesn't unwind at all: a fault sends the thread straight back
+//! to it, as `siglongjmp` would, abandoning the frames in between without
+//! running their cleanups.  It works whatever the compiler made of them.
+//!
+//! The handler runs on the thread's alternate signal stack, so that it can
+//! handle a fault from a thread which has run out of stack.  [`install`]
+//! sets one up for the calling thread, and [`spawn`] for a new thread,
+//! other threads need [`install_altstack`].
+//!
+//! A hook set with [`set_fault_hook`] is told of each such fault first, from
+//! within the signal handler, to report it.
+
+use std::cell::Cell;
+use std::fmt;
+use std::mem;
+use std::panic::{self, panic_any, AssertUnwindSafe, UnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+use libc::{c_int, c_short, c_void};
+
+mod altstack;
+mod arch;
+mod report;
+
+pub use altstack::{install_altstack, spawn, ALTSTACK_SIZE};
+pub use report::{set_fault_hook, write_backtrace, SignalWriter};
+
+/// `si_code` of a fault raised by a protection key, missing from libc
+const SEGV_PKUERR: c_int = 4;
+
+/// How the faulting instruction tried to access memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The panic payload for a protection key fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentationViolation {
+    address: usize,
+    key: c_int,
+    access: Access,
+    rights: Option<u32>,
+}
+
+impl SegmentationViolation {
+    /// The address whose access faulted
+    pub fn address(&self) -> *const u8 {
+        self.address as _
+    }
+
+    /// The protection key denying the access
+    pub fn key(&self) -> c_int {
+        self.key
+    }
+
+    /// Whether the faulting instruction was reading or writing
+    pub fn access(&self) -> Access {
+        self.access
+    }
+
+    /// The faulting thread's rights to every key when it faulted, two bits
+    /// per key as in x86's PKRU register, if they could be recovered
+    pub fn rights(&self) -> Option<u32> {
+        self.rights
+    }
+
+    /// The faulting thread's rights to `key` when it faulted, as passed to
+    /// `pkey_set`
+    pub fn rights_to(&self, key: c_int) -> Option<c_int> {
+        let shift = u32::try_from(key).ok()?.checked_mul(2)?;
+        Some((self.rights?.checked_shr(shift)? & 3) as c_int)
+    }
+}
+
+impl fmt::Display for SegmentationViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "reading",
+            Access::Write => "writing",
+        };
+        write!(
+            f,
+            "protection key {} denied {access} {:#x}",
+            self.key, self.address
+        )
+    }
+}
+
+/// The protection keys whose faults become panics, one bit per key
+static KEYS: AtomicU32 = AtomicU32::new(0);
+
+fn key_bit(key: c_int) -> u32 {
+    u32::try_from(key)
+        .ok()
+        .and_then(|key| 1u32.checked_shl(key))
+        .unwrap_or(0)
+}
+
+/// Turn faults raised by `key` into panics
+pub fn register_key(key: c_int) {
+    KEYS.fetch_or(key_bit(key), Ordering::SeqCst);
+}
+
+/// Stop turning faults raised by `key` into panics, it is being freed
+pub fn unregister_key(key: c_int) {
+    KEYS.fetch_and(!key_bit(key), Ordering::SeqCst);
+}
+
+/// Run `func`, catching a panic from it, such as a fault turned into a
+/// [`SegmentationViolation`], as [`std::panic::catch_unwind`] does
+///
+/// `func` is called such that the compiler can't tell whether it panics,
+/// so the catch is kept even if it could see `func` doesn't call anything
+/// which could.  A fault in a frame with cleanups aborts the process, see
+/// the [crate documentation](crate), so `func` must not hold drop guards
+/// around code which could fault.  Faults within `func` panic even inside
+/// a [`recover`].
+pub fn catch_fault<F: FnOnce() -> R + UnwindSafe, R>(func: F) -> std::thread::Result<R> {
+    let mut func = Some(func);
+    let mut result = None;
+    let mut call = || result = func.take().map(|func| func());
+    let call: &mut dyn FnMut() = std::hint::black_box(&mut call);
+    let outer = RECOVERY.with(|recovery| recovery.replace(ptr::null_mut()));
+    let caught = panic::catch_unwind(AssertUnwindSafe(call));
+    RECOVERY.with(|recovery| recovery.set(outer));
+    caught.map(|()| result.unwrap())
+}
+
+/// Where a fault within [`recover`] returns to
+struct Recovery {
+    regs: arch::Registers,
+    fault: Option<SegmentationViolation>,
+}
+
+thread_local! {
+    /// The innermost [`recover`] running on this thread, if any
+    static RECOVERY: Cell<*mut Recovery> = const { Cell::new(ptr::null_mut()) };
+}
+
+/// Puts back the [`recover`] enclosing the current one, however it ends
+struct RestoreRecovery(*mut Recovery);
+
+impl Drop for RestoreRecovery {
+    fn drop(&mut self) {
+        RECOVERY.with(|recovery| recovery.set(self.0));
+    }
+}
+
+/// Run `func`, returning the fault if it faults on a registered key,
+/// without unwinding
+///
+/// A fault sends the thread straight back here, as `siglongjmp` would,
+/// abandoning every frame between this call and the faulting instruction.
+/// Nothing those frames own is dropped: it is leaked, and any lock they
+/// hold stays locked.  Other panics from `func` unwind as usual.
+///
+/// The thread's rights to every key are those at the fault, including any
+/// `func` changed before faulting.
+///
+/// # Safety
+///
+/// No frame abandoned by a fault may rely on its cleanups running for
+/// soundness, as [`std::thread::scope`] and pinned values do.
+pub unsafe fn recover<F: FnOnce() -> R, R>(func: F) -> Result<R, SegmentationViolation> {
+    unsafe extern "C-unwind" fn call<G: FnOnce()>(data: *mut c_void) {
+        if let Some(func) = (*(data as *mut Option<G>)).take() {
+            func();
+        }
+    }
+
+    unsafe fn call_recoverably<G: FnOnce()>(
+        regs: &mut arch::Registers,
+        func: &mut Option<G>,
+    ) -> bool {
+        arch::segvpanic_recover_call(regs, call::<G>, func as *mut Option<G> as *mut c_void)
+    }
+
+    install();
+    let mut result = None;
+    let mut func = Some(|| result = Some(func()));
+    let mut recovery = Recovery {
+        regs: Default::default(),
+        fault: None,
+    };
+    let _restore = RestoreRecovery(RECOVERY.with(|r| r.replace(&mut recovery)));
+    let faulted = call_recoverably(&mut recovery.regs, &mut func);
+    match (faulted, recovery.fault.take()) {
+        (true, Some(fault)) => Err(fault),
+        _ => Ok(result.unwrap()),
+    }
+}
+
+/// The fault fields of the kernel's `siginfo_t`, of which libc only
+/// exposes the address
+#[repr(C)]
+struct FaultInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    addr: *mut c_void,
+    addr_lsb: c_short,
+    extra: FaultExtra,
+}
+
+#[repr(C)]
+union FaultExtra {
+    bounds: [*mut c_void; 2],
+    pkey: u32,
+}
+
+/// The key and access of a protection key fault, or `None` for any other
+unsafe fn pkey_fault(
+    info: *const libc::siginfo_t,
+    context: &libc::ucontext_t,
+) -> Option<(c_int, Access)> {
+    let info = &*(info as *const FaultInfo);
+    if info.code != SEGV_PKUERR {
+        return None;
+    }
+    Some((info.extra.pkey as c_int, arch::access(context)))
+}
+
+/// The action in place before [`install`], handed any fault we can't turn
+/// into a panic.  Never freed, a handler may be reading it at any time.
+static PREVIOUS: AtomicPtr<libc::sigaction> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    /// The fault being turned into a panic, set between redirecting the
+    /// thread and the panic starting.  A fault in that window means the
+    /// thread can't run the panic, most likely as it is out of stack.
+    static PENDING: Cell<Option<SegmentationViolation>> = const { Cell::new(None) };
+}
+
+/// Where a faulting thread is sent, called from the trampoline standing in
+/// for the faulting instruction
+extern "C-unwind" fn segvpanic() -> ! {
+    // We can panic here because the signal has "returned"
+    // so unwinding should be possible
+    match PENDING.with(Cell::take) {
+        Some(fault) => panic_any(fault),
+        None => std::process::abort(),
+    }
+}
+
+unsafe extern "C" fn sigaction_handler(
+    signum: libc::c_int,
+    info: *mut libc::siginfo_t,
+    data: *mut libc::c_void,
+) {
+    let context = &mut *(data as *mut libc::ucontext_t);
+    let Some((key, access)) = pkey_fault(info, context) else {
+        forward(signum, info, data);
+        return;
+    };
+    if KEYS.load(Ordering::SeqCst) & key_bit(key) == 0 {
+        forward(signum, info, data);
+        return;
+    }
+    let fault = SegmentationViolation {
+        address: (*info).si_addr() as usize,
+        key,
+        access,
+        rights: arch::interrupted_rights(context),
+    };
+    report::report(&fault);
+    let recovery = RECOVERY.with(Cell::get);
+    if !recovery.is_null() {
+        (*recovery).fault = Some(fault);
+        arch::resume(context, &(*recovery).regs);
+        return;
+    }
+    if PENDING
+        .with(|pending| pending.replace(Some(fault)))
+        .is_some()
+    {
+        forward(signum, info, data);
+        return;
+    }
+    arch::redirect(context);
+}
+
+/// Hand a fault to whichever action was in place before [`install`]
+unsafe fn forward(signum: libc::c_int, info: *mut libc::siginfo_t, data: *mut libc::c_void) {
+    let previous = PREVIOUS.load(Ordering::Acquire);
+    let handler = if previous.is_null() {
+        libc::SIG_DFL
+    } else {
+        (*previous).sa_sigaction
+    };
+    match handler {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // Ignoring a fault would only fault again, so either way restore
+            // the default and return, the faulting instruction will run
+            // again and the kernel will kill the process
+            let mut default: libc::sigaction = mem::zeroed();
+            default.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(signum, &default, ptr::null_mut());
+        }
+        handler if (*previous).sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: unsafe extern "C" fn(
+                libc::c_int,
+                *mut libc::siginfo_t,
+                *mut libc::c_void,
+            ) = mem::transmute(handler);
+            handler(signum, info, data);
+        }
+        handler => {
+            let handler: unsafe extern "C" fn(libc::c_int) = mem::transmute(handler);
+            handler(signum);
+        }
+    }
+}
+
+/// Turn faults on registered protection keys in every thread into panics
+///
+/// Other faults, and those which can't be turned into a panic because the
+/// thread faults again before it can start panicking, are passed to
+/// whichever SIGSEGV handler was installed before.  Installing twice has no
+/// further effect.
+///
+/// The calling thread is given an alternate signal stack, see
+/// [`install_altstack`].
+pub fn install() {
+    let _ = install_altstack();
+    unsafe {
+        let mut current: libc::sigaction = mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, ptr::null(), &mut current);
+        if current.sa_sigaction == sigaction_handler as *const () as usize {
+            return;
+        }
+        report::prepare();
+        let previous = Box::into_raw(Box::new(current));
+        PREVIOUS.store(previous, Ordering::Release);
+
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        action.sa_sigaction = sigaction_handler as *const () as usize;
+        libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut());
+    }
+}
+
+/// Restore the SIGSEGV handler which was in place before [`install`]
+pub fn uninstall() {
+    unsafe {
+        let mut current: libc::sigaction = mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, ptr::null(), &mut current);
+        if current.sa_sigaction != sigaction_handler as *const () as usize {
+            return;
+        }
+        let previous = PREVIOUS.load(Ordering::Acquire);
+        if !previous.is_null() {
+            libc::sigaction(libc::SIGSEGV, previous, ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    extern "C" {
+        fn pkey_alloc(flags: libc::c_uint, rights: c_int) -> c_int;
+        fn pkey_mprotect(addr: *mut c_void, len: libc::size_t, prot: c_int, pkey: c_int) -> c_int;
+    }
+
+    const PKEY_DISABLE_ACCESS: c_int = 1;
+    const PKEY_DISABLE_WRITE: c_int = 2;
+
+    /// A page under a new key, with the calling thread's rights set to
+    /// `rights`
+    fn guarded_page(rights: c_int) -> (c_int, *mut u64) {
+        unsafe {
+            let key = pkey_alloc(0, rights);
+            assert!(key > 0, "no protection keys available");
+            let page = libc::mmap(
+                ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(page, libc::MAP_FAILED);
+            assert_eq!(
+                pkey_mprotect(page, 4096, libc::PROT_READ | libc::PROT_WRITE, key),
+                0
+            );
+            (key, page as _)
+        }
+    }
+
+    /// Run `func` in a forked child, returning its wait status
+    fn in_child<F: FnOnce()>(func: F) -> c_int {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                func();
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            status
+        }
+    }
+
+    /// Run `func` in a forked child, returning the signal which killed it
+    fn killed_by<F: FnOnce()>(func: F) -> Option<c_int> {
+        let status = in_child(func);
+        libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status))
+    }
+
+    #[inline(never)]
+    fn danger_func(ptr: *const u64) -> u64 {
+        unsafe { ptr.read_volatile() }
+    }
+
+    #[test]
+    fn pkey_fault_becomes_panic() {
+        install();
+        install();
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        let fault = catch_fault(|| danger_func(page)).unwrap_err();
+        let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+        assert_eq!(fault.address(), page as *const u8);
+        assert_eq!(fault.key(), key);
+        assert_eq!(fault.access(), Access::Read);
+        assert_eq!(fault.rights_to(key), Some(PKEY_DISABLE_ACCESS));
+        assert_eq!(fault.rights_to(0), Some(0));
+
+        // And again, the handler must be ready for the next fault
+        let (key, page) = guarded_page(PKEY_DISABLE_WRITE);
+        register_key(key);
+        let fault = catch_fault(|| unsafe { page.write_volatile(1) }).unwrap_err();
+        let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+        assert_eq!((fault.key(), fault.access()), (key, Access::Write));
+    }
+
+    #[test]
+    fn other_faults_fall_through() {
+        install();
+        // Not a protection key fault at all
+        assert_eq!(
+            killed_by(|| {
+                std::hint::black_box(danger_func(0x10 as *const u64));
+            }),
+            Some(libc::SIGSEGV)
+        );
+        // A key nobody registered
+        let (_, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        assert_eq!(
+            killed_by(|| {
+                std::hint::black_box(danger_func(page));
+            }),
+            Some(libc::SIGSEGV)
+        );
+    }
+
+    /// Counts its drops, to check unwinding ran the cleanups of a frame
+    struct Guard<'a>(&'a Cell<u32>);
+
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    /// Call `func` with a cleanup pending, through a pointer the compiler
+    /// can't see through, so it keeps the cleanup even in release builds
+    #[inline(never)]
+    fn guarded_call(dropped: &Cell<u32>, func: &dyn Fn() -> u64) -> u64 {
+        let _guard = Guard(dropped);
+        std::hint::black_box(func)()
+    }
+
+    #[test]
+    fn unwinds_through_leaf_function() {
+        install();
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        let dropped = Cell::new(0);
+        let fault = catch_fault(AssertUnwindSafe(|| {
+            guarded_call(&dropped, &|| guarded_call(&dropped, &|| danger_func(page)))
+        }))
+        .unwrap_err();
+        assert!(fault.is::<SegmentationViolation>());
+        assert_eq!(dropped.get(), 2);
+    }
+
+    #[inline(always)]
+    fn sum(ptr: *const u64) -> u64 {
+        unsafe { ptr.read_volatile() + ptr.add(1).read_volatile() }
+    }
+
+    #[test]
+    fn unwinds_from_inlined_fault() {
+        install();
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        let dropped = Cell::new(0);
+        let fault = catch_fault(AssertUnwindSafe(|| {
+            guarded_call(&dropped, &|| sum(page) * 2)
+        }))
+        .unwrap_err();
+        let fault = fault.downcast_ref::<SegmentationViolation>().unwrap();
+        assert_eq!(fault.address(), page as *const u8);
+        assert_eq!(dropped.get(), 1);
+    }
+
+    #[test]
+    fn recover_returns_faults() {
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        assert_eq!(unsafe { recover(|| 7) }, Ok(7));
+        let fault = unsafe { recover(|| danger_func(page)) }.unwrap_err();
+        assert_eq!((fault.address(), fault.key()), (page as *const u8, key));
+
+        // Frames between are abandoned, even the faulting one with a drop
+        // guard inlined into it, which unwinding can't get out of
+        let dropped = Cell::new(0);
+        let fault = unsafe {
+            recover(|| {
+                let _guard = Guard(&dropped);
+                let sum = sum(page);
+                guarded_call(&dropped, &|| sum)
+            })
+        };
+        assert_eq!(fault.map_err(|fault| fault.key()), Err(key));
+        assert_eq!(dropped.get(), 0);
+
+        // And the thread carries on as before
+        assert_eq!(unsafe { recover(|| 8) }, Ok(8));
+    }
+
+    #[test]
+    fn recover_passes_panics_and_inner_catches() {
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        let panic = panic::catch_unwind(|| unsafe { recover(|| panic!("not a fault")) });
+        assert_eq!(panic.unwrap_err().downcast_ref(), Some(&"not a fault"));
+
+        // The innermost catch wins
+        let inner = unsafe { recover(|| catch_fault(|| danger_func(page)).is_err()) };
+        assert_eq!(inner, Ok(true));
+        let outer = catch_fault(|| unsafe { recover(|| danger_func(page)) });
+        assert!(matches!(outer, Ok(Err(_))));
+    }
+
+    /// Fills the red zone of a leaf function and stores its stack pointer in
+    /// `rsp`, then faults reading `ptr`
+    #[cfg(target_arch = "x86_64")]
+    #[inline(never)]
+    fn red_zone_leaf(ptr: *const u64, rsp: *mut usize) -> u64 {
+        let value;
+        unsafe {
+            std::arch::asm!(
+                "mov [{rsp}], rsp",
+                "mov qword ptr [rsp - 8], {sentinel}",
+                "mov qword ptr [rsp - 128], {sentinel}",
+                "mov {value}, [{ptr}]",
+                rsp = in(reg) rsp,
+                sentinel = in(reg) RED_ZONE_SENTINEL,
+                ptr = in(reg) ptr,
+                value = out(reg) value,
+            );
+        }
+        value
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const RED_ZONE_SENTINEL: u64 = 0x5afe_2011e;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn red_zone_survives_until_unwinding() {
+        thread_local! {
+            /// The stack pointer of the faulting leaf, and then whether its
+            /// red zone was intact once the panic started
+            static LEAF: Cell<usize> = const { Cell::new(0) };
+            static INTACT: Cell<Option<bool>> = const { Cell::new(None) };
+        }
+        install();
+        let (key, page) = guarded_page(PKEY_DISABLE_ACCESS);
+        register_key(key);
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let rsp = LEAF.take() as *const u64;
+            if !rsp.is_null() {
+                let intact = unsafe {
+                    rsp.sub(1).read_volatile() == RED_ZONE_SENTINEL
+                        && rsp.sub(16).read_volatile() == RED_ZONE_SENTINEL
+                };
+                INTACT.set(Some(intact));
+            }
+            previous(info)
+        }));
+        let fault = catch_fault(|| red_zone_leaf(page, LEAF.with(Cell::as_ptr)));
+        assert!(fault.is_err());
+        assert_eq!(INTACT.take(), Some(true));
+    }
+
+    #[test]
+    fn threads_get_altstacks() {
+        /// The calling thread's alternate stack, as flags, size and base
+        fn altstack() -> (c_int, usize, usize) {
+            unsafe {
+                let mut current: libc::stack_t = mem::zeroed();
+                libc::sigaltstack(ptr::null(), &mut current);
+                (current.ss_flags, current.ss_size, current.ss_sp as usize)
+            }
+        }
+        let (first, second) = spawn(|| {
+            let first = altstack();
+            install_altstack().unwrap();
+            (first, altstack())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(first.0 & libc::SS_DISABLE, 0);
+        assert_eq!(first.1, ALTSTACK_SIZE);
+        assert_eq!(first, second);
+    }
+
+    #[inline(never)]
+    fn overflow(depth: u64) -> u64 {
+        let frame = std::hint::black_box([depth; 64]);
+        if std::hint::black_box(depth) == u64::MAX {
+            return 0;
+        }
+        overflow(depth + 1) + frame[0]
+    }
+
+    /// Exit the process with status 42, on any SIGSEGV
+    extern "C" fn exit_42(_: c_int) {
+        unsafe { libc::_exit(42) };
+    }
+
+    #[test]
+    fn handles_stack_overflow_on_altstack() {
+        install();
+        // Whether a thread which overflows its stack reaches the handler
+        // before ours, with and without an alternate stack
+        let overflows = |altstack: bool| {
+            in_child(|| unsafe {
+                uninstall();
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_flags = libc::SA_ONSTACK;
+                action.sa_sigaction = exit_42 as *const () as usize;
+                libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut());
+                install();
+                let _ = std::thread::spawn(move || {
+                    let mut disable: libc::stack_t = mem::zeroed();
+                    disable.ss_flags = libc::SS_DISABLE;
+                    libc::sigaltstack(&disable, ptr::null_mut());
+                    if altstack {
+                        install_altstack().unwrap();
+                    }
+                    overflow(0)
+                })
+                .join();
+            })
+        };
+        let status = overflows(false);
+        assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV);
+        let status = overflows(true);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 42);
+    }
+}
@@ -1,68 +1,52 @@
-#![allow(deref_nullptr)]
-
-use std::{
-    panic::panic_any,
-    ptr::{null, null_mut},
-};
-
-#[derive(Debug)]
-struct SegmentationViolation;
-
-#[inline(never)]
-fn actual_panic() -> ! {
-    panic_any(SegmentationViolation)
-}
-
-#[inline(never)]
-fn segvpanic() -> ! {
-    // We can panic here because the signal has "returned"
-    // so unwinding should be possible
-    actual_panic();
+use std::ptr;
+
+use segvpanic::SegmentationViolation;
+
+extern "C" {
+    fn pkey_alloc(flags: libc::c_uint, rights: libc::c_int) -> libc::c_int;
+    fn pkey_mprotect(
+        addr: *mut libc::c_void,
+        len: libc::size_t,
+        prot: libc::c_int,
+        pkey: libc::c_int,
+    ) -> libc::c_int;
 }
 
-unsafe extern "C" fn sigaction_handler(
-    _signum: libc::c_int,
-    _info: *mut libc::siginfo_t,
-    data: *mut libc::c_void,
-) {
-    let context = &mut *(data as *mut libc::ucontext_t);
-    let gregs = &mut context.uc_mcontext.gregs;
-    let mut rsp = gregs[libc::REG_RSP as usize] as *mut u64;
-    let rip = gregs[libc::REG_RIP as usize] as u64;
-
-    // We want to simulate the effect of a CALL to segvpanic
-    // as such, we want to decrement the stack pointer (descending stack)
-    rsp = rsp.sub(1);
-    gregs[libc::REG_RSP as usize] = rsp as i64;
-    // And write into that the old return address
-    rsp.write(rip);
-    // Before replacing the EIP of the faulting instruction with the start
-    // of segvpanic
-    gregs[libc::REG_RIP as usize] = (segvpanic as *mut libc::c_void) as i64;
-    // Now we return which allows the kernel to let us try again our faulting
-    // instruction which is actually a call to segvpanic
-}
-
-unsafe fn setup_signals() {
-    let mut action: libc::sigaction = std::mem::zeroed();
-    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
-    action.sa_sigaction = sigaction_handler as libc::sighandler_t;
-    libc::sigaction(libc::SIGSEGV, &action, null_mut());
+const PKEY_DISABLE_ACCESS: libc::c_int = 1;
+
+/// A page we aren't allowed to touch, under a key we own
+fn guarded_page() -> (libc::c_int, *const u64) {
+    unsafe {
+        let key = pkey_alloc(0, PKEY_DISABLE_ACCESS);
+        assert!(key > 0, "no protection keys available");
+        let page = libc::mmap(
+            ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        pkey_mprotect(page, 4096, libc::PROT_READ | libc::PROT_WRITE, key);
+        (key, page as _)
+    }
 }
 
-fn danger_func() -> u64 {
-    unsafe { *null::<u64>() }
+fn danger_func(page: *const u64) -> u64 {
+    unsafe { page.read_volatile() }
 }
 
 fn main() {
     println!("Hello, let's try and segfault ourselves...");
-    unsafe { setup_signals() }
+    let (key, page) = guarded_page();
+    segvpanic::register_key(key);
+    segvpanic::install();
     println!("We've set up our signal handler, now let's segfault");
-    match std::panic::catch_unwind(|| std::hint::black_box(danger_func())) {
+    match segvpanic::catch_fault(|| danger_func(page)) {
         Ok(v) => println!("WTF? {v}"),
         Err(e) => {
             if let Some(e) = e.downcast_ref::<SegmentationViolation>() {
-                eprintln!("Caught the failure: {e:?}");
+                eprintln!("Caught the failure: {e}");
             } else {
                 eprintln!("Unknown failure: {e:?}");
             }
@@ -0,0 +1,83 @@
+//! Reporting faults from within the signal handler
+//!
+//! Nothing here may allocate or take a lock, the faulting thread could have
+//! been interrupted while holding it.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::{c_int, c_void};
+
+use crate::SegmentationViolation;
+
+/// The hook set by [`set_fault_hook`], zero for none
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Have `hook` called with every fault on a registered key, before it
+/// becomes a panic
+///
+/// The hook runs within the signal handler, so must stick to async-signal
+/// safe functions: no allocating, locking or `println!`.  [`SignalWriter`]
+/// and [`write_backtrace`] are safe to use.
+pub fn set_fault_hook(hook: Option<fn(&SegmentationViolation)>) {
+    HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::SeqCst);
+}
+
+pub(crate) fn report(fault: &SegmentationViolation) {
+    let hook = HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        let hook: fn(&SegmentationViolation) = unsafe { std::mem::transmute(hook) };
+        hook(fault);
+    }
+}
+
+/// Get ready to report from a signal handler
+pub(crate) fn prepare() {
+    // The first backtrace loads the unwinder, which allocates
+    #[cfg(target_env = "gnu")]
+    unsafe {
+        let mut frame = [std::ptr::null_mut(); 1];
+        libc::backtrace(frame.as_mut_ptr(), 1);
+    }
+}
+
+/// Formats straight to a file descriptor, safe within a signal handler
+#[derive(Debug, Clone, Copy)]
+pub struct SignalWriter(pub c_int);
+
+impl SignalWriter {
+    pub fn stderr() -> Self {
+        Self(libc::STDERR_FILENO)
+    }
+}
+
+impl fmt::Write for SignalWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written =
+                unsafe { libc::write(self.0, bytes.as_ptr() as *const c_void, bytes.len()) };
+            if written <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+/// Write a backtrace of the calling thread to `fd`, one frame per line,
+/// safe within a signal handler once [`crate::install`] has been called
+pub fn write_backtrace(fd: c_int) {
+    #[cfg(target_env = "gnu")]
+    unsafe {
+        let mut frames = [std::ptr::null_mut(); 64];
+        let depth = libc::backtrace(frames.as_mut_ptr(), frames.len() as c_int);
+        libc::backtrace_symbols_fd(frames.as_ptr(), depth, fd);
+    }
+    #[cfg(not(target_env = "gnu"))]
+    {
+        use fmt::Write;
+        let _ = writeln!(SignalWriter(fd), "backtrace unavailable");
+    }
+}